@@ -0,0 +1,82 @@
+//! Recent deliveries and failures, kept in memory for the bot's `/status`
+//! and `/history` commands.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+
+/// Sent messages we remember.
+const MAX_SENT: usize = 100;
+
+/// Failures we remember.
+const MAX_ERRORS: usize = 10;
+
+/// Longest summary kept of a message, in characters.
+const SUMMARY_LEN: usize = 80;
+
+#[derive(Clone)]
+pub struct Entry {
+    pub at: DateTime<Utc>,
+    pub destination: String,
+    /// The first line of a sent message, or the error of a failed one.
+    pub text: String,
+}
+
+pub struct Activity {
+    pub started_at: Instant,
+    sent: Mutex<VecDeque<Entry>>,
+    errors: Mutex<VecDeque<Entry>>,
+}
+
+impl Default for Activity {
+    fn default() -> Activity {
+        Activity {
+            started_at: Instant::now(),
+            sent: Mutex::new(VecDeque::new()),
+            errors: Mutex::new(VecDeque::new()),
+        }
+    }
+}
+
+fn push(list: &Mutex<VecDeque<Entry>>, max: usize, destination: &str, text: String) {
+    let mut list = list.lock().unwrap();
+    if list.len() >= max {
+        list.pop_front();
+    }
+    list.push_back(Entry {
+        at: Utc::now(),
+        destination: destination.to_string(),
+        text,
+    });
+}
+
+impl Activity {
+    pub fn sent(&self, destination: &str, text: &str) {
+        let line = text.lines().find(|l| !l.trim().is_empty()).unwrap_or("");
+        let mut summary: String = line.trim().chars().take(SUMMARY_LEN).collect();
+        if line.trim().chars().count() > SUMMARY_LEN {
+            summary.push('…');
+        }
+        push(&self.sent, MAX_SENT, destination, summary);
+    }
+
+    pub fn failed(&self, destination: &str, error: &str) {
+        push(&self.errors, MAX_ERRORS, destination, error.to_string());
+    }
+
+    /// The last `n` sent messages, oldest first.
+    pub fn recent_sent(&self, n: usize) -> Vec<Entry> {
+        let sent = self.sent.lock().unwrap();
+        sent.iter()
+            .skip(sent.len().saturating_sub(n))
+            .cloned()
+            .collect()
+    }
+
+    /// Remembered failures, oldest first.
+    pub fn recent_errors(&self) -> Vec<Entry> {
+        self.errors.lock().unwrap().iter().cloned().collect()
+    }
+}
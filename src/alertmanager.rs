@@ -0,0 +1,81 @@
+//! `POST /alertmanager`: Prometheus Alertmanager webhook receiver.
+//!
+//! The payload (`version: "4"`) is rendered as-is through a template, so a
+//! custom template can use any field Alertmanager sends: `status`,
+//! `groupKey`, `groupLabels`, `commonLabels`, `commonAnnotations`,
+//! `externalURL` and `alerts[]` with their `labels`, `annotations`,
+//! `startsAt`, `endsAt` and `generatorURL`.
+
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::{Request, Response, StatusCode};
+
+use crate::config::AlertmanagerConfig;
+use crate::telegram::Destination;
+use crate::template::Template;
+use crate::{hook, json_response, overflow_header, parse_mode_name, AppState, BoxError};
+
+/// Firing alerts first, then resolved ones, one line per alert with its
+/// severity, summary and a link to the expression that fired.
+const DEFAULT_TEMPLATE: &str = r#"
+{% set firing = alerts | selectattr("status", "eq", "firing") | list %}
+{% set resolved = alerts | selectattr("status", "eq", "resolved") | list %}
+{% if firing %}
+🔥 <b>FIRING ({{ firing | length }})</b>{{ " " ~ groupLabels.alertname if groupLabels.alertname }}
+{% for a in firing %}
+• <b>{{ a.labels.alertname }}</b>{{ " [" ~ a.labels.severity ~ "]" if a.labels.severity }}{{ ": " ~ (a.annotations.summary or a.annotations.description) if a.annotations.summary or a.annotations.description }}{% if a.generatorURL %} <a href="{{ a.generatorURL }}">source</a>{% endif %}
+
+{% endfor %}
+
+{% endif %}
+{% if resolved %}
+✅ <b>RESOLVED ({{ resolved | length }})</b>{{ " " ~ groupLabels.alertname if groupLabels.alertname }}
+{% for a in resolved %}
+• <b>{{ a.labels.alertname }}</b>{{ " [" ~ a.labels.severity ~ "]" if a.labels.severity }}{{ ": " ~ a.annotations.summary if a.annotations.summary }}
+
+{% endfor %}
+{% endif %}
+"#;
+
+/// Build the template from config, falling back to the built-in one.
+pub fn template(config: Option<&AlertmanagerConfig>) -> Result<Template, String> {
+    let source = config.and_then(|c| c.template.as_deref());
+    let parse_mode = match config.and_then(|c| c.parse_mode.as_deref()) {
+        Some(name) => parse_mode_name(name),
+        None => Some("HTML"),
+    };
+    Template::new(source.unwrap_or(DEFAULT_TEMPLATE), parse_mode)
+        .map_err(|e| format!("alertmanager template: {}", e))
+}
+
+pub async fn handle(
+    req: Request<Bytes>,
+    state: &AppState,
+    dest: &Destination,
+) -> Result<Response<Full<Bytes>>, BoxError> {
+    let overflow = overflow_header(req.headers());
+    let payload: serde_json::Value = match serde_json::from_slice(req.body()) {
+        Ok(v) => v,
+        Err(e) => {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                serde_json::json!({"error": format!("invalid JSON: {}", e)}),
+            );
+        }
+    };
+    if !payload["alerts"].is_array() {
+        return json_response(
+            StatusCode::BAD_REQUEST,
+            serde_json::json!({"error": "not an Alertmanager payload: missing alerts"}),
+        );
+    }
+
+    hook::render_and_deliver(
+        state,
+        dest,
+        &state.alertmanager_template,
+        &payload,
+        overflow,
+    )
+    .await
+}
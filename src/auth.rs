@@ -0,0 +1,96 @@
+//! API key authentication. A key is sent as `Authorization: Bearer <key>`
+//! or `X-Api-Key: <key>` and may be limited to some destinations and
+//! routes. With no keys configured every request is allowed.
+
+use hyper::HeaderMap;
+
+use crate::config::ApiKeyConfig;
+
+pub enum AuthError {
+    Missing,
+    Invalid,
+    Forbidden(String),
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "missing API key"),
+            AuthError::Invalid => write!(f, "invalid API key"),
+            AuthError::Forbidden(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+/// Compare without short-circuiting so response timing doesn't reveal how
+/// much of a key matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub fn presented_key(headers: &HeaderMap) -> Option<&str> {
+    let bearer = headers
+        .get(hyper::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| {
+            let (scheme, token) = v.trim().split_once(' ')?;
+            scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+        });
+    bearer.or_else(|| {
+        headers
+            .get("x-api-key")
+            .and_then(|v| v.to_str().ok())
+            .map(str::trim)
+    })
+}
+
+/// Check that the `presented` key, usually from [`presented_key`], may use
+/// `route` for `destination`.
+pub fn check(
+    keys: &[ApiKeyConfig],
+    presented: Option<&str>,
+    route: &str,
+    destination: &str,
+) -> Result<(), AuthError> {
+    if keys.is_empty() {
+        return Ok(());
+    }
+    let presented = presented.ok_or(AuthError::Missing)?;
+
+    // Compare against every key so the match position doesn't leak either.
+    let mut matched = None;
+    for key in keys {
+        if constant_time_eq(key.key.as_bytes(), presented.as_bytes()) && matched.is_none() {
+            matched = Some(key);
+        }
+    }
+    let key = matched.ok_or(AuthError::Invalid)?;
+
+    if let Some(routes) = &key.routes {
+        // A scope also covers everything under it: `hook` allows `hook/x`.
+        let covers = |r: &String| {
+            route == r
+                || route
+                    .strip_prefix(r.as_str())
+                    .is_some_and(|rest| rest.starts_with('/'))
+        };
+        if !routes.iter().any(covers) {
+            return Err(AuthError::Forbidden(format!(
+                "API key {} may not use route {}",
+                key.name, route
+            )));
+        }
+    }
+    if let Some(destinations) = &key.destinations {
+        if !destinations.iter().any(|d| d == destination) {
+            return Err(AuthError::Forbidden(format!(
+                "API key {} may not send to {}",
+                key.name, destination
+            )));
+        }
+    }
+    Ok(())
+}
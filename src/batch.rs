@@ -0,0 +1,270 @@
+//! Digests: destinations with `batch` configured collect their messages
+//! and send them as one message per window.
+//!
+//! A batch opens with its first message and is sent `window_secs` later,
+//! or as soon as it holds `max_messages`. Messages in one digest share a
+//! parse mode; a message with a different one sends the open batch early.
+//! Batches are kept in `<data_dir>/batches.json` until they are sent, so
+//! they survive a restart.
+
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::config::BatchConfig;
+use crate::telegram::MessageOptions;
+use crate::template::escape;
+use crate::{persist, send_parts, split, AppState, BoxError};
+
+const SEPARATOR: &str = "──────────";
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Batch {
+    opened_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parse_mode: Option<String>,
+    /// Only a batch of silent messages is sent silently.
+    silent: bool,
+    /// A digest with an urgent message rings even during quiet hours.
+    urgent: bool,
+    messages: Vec<String>,
+}
+
+/// Batches not yet sent, as saved in `batches.json`.
+#[derive(Clone, Default, Deserialize, Serialize)]
+struct Pending {
+    /// The open batch of each destination.
+    #[serde(default)]
+    open: HashMap<String, Batch>,
+    /// Closed batches waiting for the worker, oldest first.
+    #[serde(default)]
+    ready: VecDeque<(String, Batch)>,
+}
+
+pub struct Batcher {
+    path: PathBuf,
+    configs: HashMap<String, BatchConfig>,
+    pending: Mutex<Pending>,
+    notify: Notify,
+}
+
+impl Batcher {
+    /// `configs` holds the batch settings of each batched destination.
+    pub fn open(path: PathBuf, configs: HashMap<String, BatchConfig>) -> Result<Batcher, BoxError> {
+        let mut pending: Pending = persist::load(&path)?;
+        // Batches of destinations no longer batched go out right away.
+        let unbatched: Vec<String> = pending
+            .open
+            .keys()
+            .filter(|name| !configs.contains_key(*name))
+            .cloned()
+            .collect();
+        for name in unbatched {
+            let batch = pending.open.remove(&name).unwrap();
+            pending.ready.push_back((name, batch));
+        }
+        let count: usize = pending
+            .open
+            .values()
+            .chain(pending.ready.iter().map(|(_, b)| b))
+            .map(|b| b.messages.len())
+            .sum();
+        if count > 0 {
+            info!("loaded batched messages", count = count);
+        }
+        Ok(Batcher {
+            path,
+            configs,
+            pending: Mutex::new(pending),
+            notify: Notify::new(),
+        })
+    }
+
+    /// Add a message to the destination's batch. Returns how many messages
+    /// the batch holds, or `None` if the destination isn't batched.
+    pub fn add(
+        &self,
+        destination: &str,
+        text: &str,
+        options: &MessageOptions,
+    ) -> Result<Option<usize>, BoxError> {
+        let Some(config) = self.configs.get(destination) else {
+            return Ok(None);
+        };
+        let mut pending = self.pending.lock().unwrap();
+        let before = pending.clone();
+
+        if pending
+            .open
+            .get(destination)
+            .is_some_and(|b| b.parse_mode != options.parse_mode)
+        {
+            let batch = pending.open.remove(destination).unwrap();
+            pending.ready.push_back((destination.to_string(), batch));
+        }
+
+        let batch = pending
+            .open
+            .entry(destination.to_string())
+            .or_insert_with(|| Batch {
+                opened_at: Utc::now(),
+                parse_mode: options.parse_mode.clone(),
+                silent: true,
+                urgent: false,
+                messages: Vec::new(),
+            });
+        batch.silent &= options.silent;
+        batch.urgent |= options.urgent;
+        batch.messages.push(text.to_string());
+        let count = batch.messages.len();
+
+        if count >= config.max_messages {
+            let batch = pending.open.remove(destination).unwrap();
+            pending.ready.push_back((destination.to_string(), batch));
+        }
+        if let Err(e) = persist::save(&self.path, &*pending) {
+            *pending = before;
+            return Err(e);
+        }
+        // Also wakes the worker to schedule a newly opened batch.
+        self.notify.notify_one();
+        Ok(Some(count))
+    }
+
+    /// Close every batch whose window has passed, and return the time
+    /// until the next open one's does.
+    fn close_due(&self) -> Option<Duration> {
+        let mut pending = self.pending.lock().unwrap();
+        let now = Utc::now();
+        let mut next: Option<Duration> = None;
+        let mut closed = false;
+        let names: Vec<String> = pending.open.keys().cloned().collect();
+        for name in names {
+            let window = Duration::from_secs(self.configs[&name].window_secs);
+            let age = (now - pending.open[&name].opened_at)
+                .to_std()
+                .unwrap_or_default();
+            if age >= window {
+                let batch = pending.open.remove(&name).unwrap();
+                pending.ready.push_back((name, batch));
+                closed = true;
+            } else {
+                let left = window - age;
+                next = Some(next.map_or(left, |n| n.min(left)));
+            }
+        }
+        if closed {
+            if let Err(e) = persist::save(&self.path, &*pending) {
+                error!("failed to save batches", error = e);
+            }
+        }
+        next
+    }
+
+    /// The oldest batch waiting to be sent.
+    fn first_ready(&self) -> Option<(String, Batch)> {
+        self.pending.lock().unwrap().ready.front().cloned()
+    }
+
+    /// Forget the oldest ready batch, once it has been sent.
+    fn sent(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.ready.pop_front();
+        if let Err(e) = persist::save(&self.path, &*pending) {
+            error!("failed to save batches", error = e);
+        }
+    }
+}
+
+/// Join a batch into one digest text.
+fn digest(batch: &Batch, title: &str) -> String {
+    if let [only] = batch.messages.as_slice() {
+        return only.clone();
+    }
+    let parse_mode = batch.parse_mode.as_deref();
+    let title = escape(title, parse_mode);
+    let count = escape(&format!("· {} messages", batch.messages.len()), parse_mode);
+    let header = match parse_mode {
+        Some("HTML") => format!("<b>{}</b> {}", title, count),
+        Some("MarkdownV2") => format!("*{}* {}", title, count),
+        _ => format!("{} {}", title, count),
+    };
+    let mut out = header;
+    for message in &batch.messages {
+        out.push_str("\n\n");
+        out.push_str(SEPARATOR);
+        out.push('\n');
+        out.push_str(message);
+    }
+    out
+}
+
+async fn send(state: &AppState, name: &str, batch: Batch) {
+    let Some(dest) = state.destination(Some(name)) else {
+        warn!(
+            "dropping digest for unknown destination",
+            destination = name,
+            messages = batch.messages.len()
+        );
+        return;
+    };
+    let title = state
+        .batcher
+        .configs
+        .get(name)
+        .and_then(|c| c.title.as_deref())
+        .unwrap_or("Digest");
+    let text = digest(&batch, title);
+    let options = MessageOptions {
+        parse_mode: batch.parse_mode.clone(),
+        silent: batch.silent,
+        urgent: batch.urgent,
+        ..Default::default()
+    };
+    let parts = split::split_message(&text, options.parse_mode.as_deref());
+    info!(
+        "sending digest",
+        destination = name,
+        messages = batch.messages.len(),
+        parts = parts.len()
+    );
+
+    if let Some(outbox) = &state.outbox {
+        for part in parts {
+            if let Err(e) = outbox.enqueue(name, part, options.clone(), false) {
+                error!("failed to queue digest", destination = name, error = e);
+                return;
+            }
+        }
+        return;
+    }
+    if let Err(e) = send_parts(state, &dest, &parts, &options, false).await {
+        error!("failed to send digest", destination = name, error = e);
+    }
+}
+
+/// Send batches as their windows close.
+pub async fn run_worker(state: Arc<AppState>) {
+    let batcher = &state.batcher;
+    if batcher.configs.is_empty() && batcher.first_ready().is_none() {
+        return;
+    }
+    loop {
+        let next = batcher.close_due();
+        while let Some((name, batch)) = batcher.first_ready() {
+            send(&state, &name, batch).await;
+            batcher.sent();
+        }
+        match next {
+            Some(wait) => {
+                let _ = tokio::time::timeout(wait, batcher.notify.notified()).await;
+            }
+            None => batcher.notify.notified().await,
+        }
+    }
+}
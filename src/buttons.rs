@@ -0,0 +1,384 @@
+//! Callback buttons and their answers.
+//!
+//! A message with callback buttons is tracked by destination and message
+//! id. The first press is the answer: it is recorded, shown under the
+//! message, and returned by `GET /messages/<id>/response`, which can
+//! long-poll with `?wait=` until someone answers. With `updates.admins`
+//! set, only those users' presses count.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{SecondsFormat, Utc};
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::{Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::watch;
+
+use crate::telegram::{call_api, inline_keyboard, Button, Destination};
+use crate::{commands, duration, json_response, AppState, BoxError};
+
+/// Messages whose answers we keep; the oldest are forgotten first.
+const MAX_TRACKED: usize = 10_000;
+
+/// Longest `?wait=` we hold a request open for.
+const MAX_WAIT: Duration = Duration::from_secs(300);
+
+/// Telegram's limit on `callback_data`, in bytes.
+const MAX_CALLBACK_LEN: usize = 64;
+
+/// The `buttons` request field: one row of buttons, or a list of rows.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Layout {
+    Rows(Vec<Vec<Button>>),
+    Row(Vec<Button>),
+}
+
+impl Layout {
+    pub fn into_rows(self) -> Vec<Vec<Button>> {
+        match self {
+            Layout::Rows(rows) => rows,
+            Layout::Row(row) => vec![row],
+        }
+    }
+}
+
+pub fn validate(rows: &[Vec<Button>]) -> Result<(), String> {
+    for button in rows.iter().flatten() {
+        if button.text.trim().is_empty() {
+            return Err("button text is empty".to_string());
+        }
+        if button.url.is_some() && button.callback.is_some() {
+            return Err(format!(
+                "button {:?}: set url or callback, not both",
+                button.text
+            ));
+        }
+        if let Some(data) = button.callback_data() {
+            if data.is_empty() || data.len() > MAX_CALLBACK_LEN {
+                return Err(format!(
+                    "button {:?}: callback must be 1-{} bytes",
+                    button.text, MAX_CALLBACK_LEN
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn has_callbacks(rows: &[Vec<Button>]) -> bool {
+    rows.iter().flatten().any(|b| b.callback_data().is_some())
+}
+
+#[derive(Clone, Serialize)]
+pub struct Answer {
+    /// The pressed button's callback data.
+    pub data: String,
+    /// The pressed button's label.
+    pub text: String,
+    pub user: User,
+    pub answered_at: String,
+}
+
+#[derive(Clone, Serialize)]
+pub struct User {
+    pub id: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    pub name: String,
+}
+
+impl User {
+    pub fn from_telegram(from: &Value) -> User {
+        let name = [&from["first_name"], &from["last_name"]]
+            .iter()
+            .filter_map(|v| v.as_str())
+            .collect::<Vec<_>>()
+            .join(" ");
+        User {
+            id: from["id"].as_i64().unwrap_or_default(),
+            username: from["username"].as_str().map(String::from),
+            name,
+        }
+    }
+
+    /// `@username`, or the name for users without one.
+    pub fn display(&self) -> String {
+        match &self.username {
+            Some(u) => format!("@{}", u),
+            None => self.name.clone(),
+        }
+    }
+}
+
+type Key = (String, i64);
+
+#[derive(Default)]
+struct Tracked {
+    slots: HashMap<Key, watch::Sender<Option<Answer>>>,
+    order: VecDeque<Key>,
+}
+
+/// Answers to callback buttons, by destination name and message id.
+#[derive(Default)]
+pub struct Answers {
+    inner: Mutex<Tracked>,
+}
+
+impl Answers {
+    fn slot(inner: &mut Tracked, key: Key) -> watch::Sender<Option<Answer>> {
+        if let Some(tx) = inner.slots.get(&key) {
+            return tx.clone();
+        }
+        if inner.order.len() >= MAX_TRACKED {
+            if let Some(old) = inner.order.pop_front() {
+                inner.slots.remove(&old);
+            }
+        }
+        let (tx, _) = watch::channel(None);
+        inner.slots.insert(key.clone(), tx.clone());
+        inner.order.push_back(key);
+        tx
+    }
+
+    /// Start waiting for an answer to a message that was just sent, or
+    /// edited, with callback buttons. An earlier answer is forgotten.
+    pub fn track(&self, destination: &str, message_id: i64) {
+        let mut inner = self.inner.lock().unwrap();
+        Self::slot(&mut inner, (destination.to_string(), message_id)).send_replace(None);
+    }
+
+    /// Record `answer` unless the message was answered already. Messages
+    /// we don't know about (sent before a restart) are tracked from here.
+    fn record(&self, destination: &str, message_id: i64, answer: Answer) -> bool {
+        let tx = {
+            let mut inner = self.inner.lock().unwrap();
+            Self::slot(&mut inner, (destination.to_string(), message_id))
+        };
+        tx.send_if_modified(|current| {
+            if current.is_some() {
+                return false;
+            }
+            *current = Some(answer);
+            true
+        })
+    }
+
+    fn subscribe(
+        &self,
+        destination: &str,
+        message_id: i64,
+    ) -> Option<watch::Receiver<Option<Answer>>> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .slots
+            .get(&(destination.to_string(), message_id))
+            .map(|tx| tx.subscribe())
+    }
+}
+
+/// `GET /messages/<id>/response[?wait=60s]`: the answer to a message's
+/// buttons, waiting up to `wait` for one.
+pub async fn handle_response(
+    req: Request<Bytes>,
+    state: &AppState,
+    dest: &Destination,
+    message_id: i64,
+) -> Result<Response<Full<Bytes>>, BoxError> {
+    let query = req.uri().query().unwrap_or("");
+    let wait = form_urlencoded::parse(query.as_bytes())
+        .find(|(k, _)| k == "wait")
+        .map(|(_, v)| v.into_owned());
+    let wait = match wait {
+        Some(w) => match duration::parse(&w) {
+            Some(d) => d.min(MAX_WAIT),
+            None => {
+                return json_response(
+                    StatusCode::BAD_REQUEST,
+                    serde_json::json!({"error": format!("invalid wait: {}", w)}),
+                );
+            }
+        },
+        None => Duration::ZERO,
+    };
+
+    let rx = state
+        .answers
+        .as_ref()
+        .and_then(|a| a.subscribe(&dest.name, message_id));
+    let Some(mut rx) = rx else {
+        return json_response(
+            StatusCode::NOT_FOUND,
+            serde_json::json!({"error": format!("no buttons on message {}", message_id)}),
+        );
+    };
+
+    // A timeout leaves the answer unset, which reads as pending.
+    let _ = tokio::time::timeout(wait, rx.wait_for(Option::is_some)).await;
+    let answer = rx.borrow().clone();
+    match answer {
+        Some(answer) => json_response(
+            StatusCode::OK,
+            serde_json::json!({
+                "status": "answered",
+                "message_id": message_id,
+                "data": answer.data,
+                "text": answer.text,
+                "user": answer.user,
+                "answered_at": answer.answered_at,
+            }),
+        ),
+        None => json_response(
+            StatusCode::OK,
+            serde_json::json!({"status": "pending", "message_id": message_id}),
+        ),
+    }
+}
+
+/// Handle a `callback_query` update received through the bot `token`.
+pub async fn on_callback(state: &AppState, token: &str, query: &Value) {
+    let Some(answers) = &state.answers else {
+        return;
+    };
+    let message = &query["message"];
+    let chat_id = message["chat"]["id"].as_i64().unwrap_or_default();
+    let message_id = message["message_id"].as_i64().unwrap_or_default();
+    let data = query["data"].as_str().unwrap_or_default().to_string();
+    let user = User::from_telegram(&query["from"]);
+
+    // With `admins` set, nobody else can answer, so that in a group not
+    // every member can approve a deploy.
+    if !state.admins.is_empty() && !commands::is_admin(state, &query["from"]) {
+        warn!(
+            "ignoring button press from unauthorized user",
+            chat_id = chat_id,
+            message_id = message_id,
+            user = user.display(),
+            user_id = user.id
+        );
+        answer_query(state, token, query, "Not allowed").await;
+        return;
+    }
+
+    // Find the pressed button's label in the message's keyboard.
+    let keyboard = &message["reply_markup"]["inline_keyboard"];
+    let text = keyboard
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_array)
+        .flatten()
+        .find(|b| b["callback_data"].as_str() == Some(&data))
+        .and_then(|b| b["text"].as_str())
+        .unwrap_or(&data)
+        .to_string();
+
+    let dest = state
+        .destinations
+        .values()
+        .find(|d| d.token == token && d.chat_id == chat_id);
+    let recorded = match dest {
+        Some(dest) => {
+            let answer = Answer {
+                data: data.clone(),
+                text: text.clone(),
+                user: user.clone(),
+                answered_at: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            };
+            answers.record(&dest.name, message_id, answer)
+        }
+        None => false,
+    };
+    info!(
+        "button pressed",
+        chat_id = chat_id,
+        message_id = message_id,
+        data = data,
+        user = user.display(),
+        recorded = recorded
+    );
+
+    let toast = if recorded {
+        "Answer recorded"
+    } else {
+        "Already answered"
+    };
+    answer_query(state, token, query, toast).await;
+
+    if recorded {
+        if let Err(e) = show_answer(state, token, message, &text, &user).await {
+            warn!(
+                "failed to show button answer",
+                message_id = message_id,
+                error = e
+            );
+        }
+    }
+}
+
+/// Stop the pressed button's spinner, showing `toast` to whoever pressed it.
+async fn answer_query(state: &AppState, token: &str, query: &Value, toast: &str) {
+    let query_id = query["id"].as_str().unwrap_or_default();
+    let body = serde_json::json!({"callback_query_id": query_id, "text": toast});
+    if let Err(e) = call_api(state, token, "answerCallbackQuery", None, |r| r.json(&body)).await {
+        warn!("failed to answer callback query", error = e);
+    }
+}
+
+/// Append the answer to the message and drop its callback buttons, so
+/// everyone in the chat sees it was answered. Link buttons stay.
+async fn show_answer(
+    state: &AppState,
+    token: &str,
+    message: &Value,
+    text: &str,
+    user: &User,
+) -> Result<(), BoxError> {
+    // Keeping the original entities keeps the formatting; appending text
+    // doesn't move their offsets.
+    let (method, field, entities) = if message["text"].is_string() {
+        ("editMessageText", "text", "entities")
+    } else if message["caption"].is_string() {
+        ("editMessageCaption", "caption", "caption_entities")
+    } else {
+        return Ok(());
+    };
+    let original = message[field].as_str().unwrap_or_default();
+    let chat_id = message["chat"]["id"].as_i64().unwrap_or_default();
+
+    let links: Vec<Vec<Button>> = message["reply_markup"]["inline_keyboard"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|row| {
+            row.as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|b| {
+                    Some(Button {
+                        text: b["text"].as_str()?.to_string(),
+                        url: Some(b["url"].as_str()?.to_string()),
+                        callback: None,
+                    })
+                })
+                .collect::<Vec<_>>()
+        })
+        .filter(|row| !row.is_empty())
+        .collect();
+
+    let mut body = serde_json::json!({
+        "chat_id": chat_id,
+        "message_id": message["message_id"],
+        field: format!("{}\n\n{} — {}", original, text, user.display()),
+        "reply_markup": inline_keyboard(&links),
+    });
+    if message[entities].is_array() {
+        body[entities] = message[entities].clone();
+    }
+    call_api(state, token, method, Some(chat_id), |r| r.json(&body)).await?;
+    Ok(())
+}
@@ -0,0 +1,213 @@
+//! Bot commands, for controlling the relay from Telegram. Only users
+//! listed in `updates.admins` are answered; everyone else is ignored.
+//!
+//! - `/status`: uptime, queues, mute and the last delivery errors
+//! - `/mute [duration]`: hold non-urgent messages, until `/unmute` if no
+//!   duration is given
+//! - `/unmute`: send what was held and stop holding
+//! - `/history [n]`: the last `n` messages sent (10 by default)
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde_json::Value;
+
+use crate::telegram::call_api;
+use crate::{buttons, duration, split, AppState};
+
+/// Most messages `/history` lists.
+const MAX_HISTORY: usize = 50;
+
+/// Commands as shown in Telegram's command menu.
+pub const COMMANDS: [(&str, &str); 5] = [
+    ("status", "Uptime, queues and recent errors"),
+    ("mute", "Hold non-urgent messages, e.g. /mute 2h"),
+    ("unmute", "Send held messages and stop holding"),
+    ("history", "Recent messages, e.g. /history 10"),
+    ("help", "List commands"),
+];
+
+/// Whether `from` is one of `admins`, by id or username.
+pub fn is_admin(state: &AppState, from: &Value) -> bool {
+    let id = from["id"].as_i64().map(|id| id.to_string());
+    let username = from["username"].as_str();
+    state.admins.iter().any(|a| {
+        let a = a.trim();
+        Some(a) == id.as_deref()
+            || username.is_some_and(|u| a.trim_start_matches('@').eq_ignore_ascii_case(u))
+    })
+}
+
+fn time(t: DateTime<Utc>) -> String {
+    t.format("%Y-%m-%d %H:%M:%S UTC").to_string()
+}
+
+fn help() -> String {
+    let mut out = String::from("Commands:");
+    for (name, description) in COMMANDS {
+        out.push_str(&format!("\n/{} — {}", name, description));
+    }
+    out
+}
+
+fn status(state: &AppState) -> String {
+    let uptime = duration::format(state.activity.started_at.elapsed());
+    let mut out = format!("Relay up {}", uptime);
+    if let Some(outbox) = &state.outbox {
+        out.push_str(&format!("\nOutbox: {} queued", outbox.len()));
+    }
+    out.push_str(&format!(
+        "\nHeld: {} · Scheduled: {}",
+        state.held.len(),
+        state.schedule.len()
+    ));
+    match state.mute.until() {
+        Some(Some(until)) => out.push_str(&format!("\nMuted until {}", time(until))),
+        Some(None) => out.push_str("\nMuted until /unmute"),
+        None => {}
+    }
+
+    let errors = state.activity.recent_errors();
+    if errors.is_empty() {
+        out.push_str("\n\nNo delivery errors");
+    } else {
+        out.push_str("\n\nLast errors:");
+        for e in errors.iter().rev() {
+            out.push_str(&format!(
+                "\n• {} → {}: {}",
+                time(e.at),
+                e.destination,
+                e.text
+            ));
+        }
+    }
+    out
+}
+
+fn mute(state: &AppState, arg: &str) -> String {
+    let until = if arg.is_empty() {
+        None
+    } else {
+        let at = duration::parse(arg)
+            .filter(|d| !d.is_zero())
+            .and_then(|d| TimeDelta::from_std(d).ok())
+            .and_then(|d| Utc::now().checked_add_signed(d));
+        match at {
+            Some(at) => Some(at),
+            None => return format!("Invalid duration: {}. Try /mute 2h", arg),
+        }
+    };
+    if let Err(e) = state.mute.mute(until) {
+        error!("failed to save mute", error = e);
+        return format!("Failed to mute: {}", e);
+    }
+    info!("muted", until = until.map(time).unwrap_or_default());
+    match until {
+        Some(t) => format!(
+            "Muted until {}. Urgent messages still go out; /unmute to end early.",
+            time(t)
+        ),
+        None => "Muted until /unmute. Urgent messages still go out.".to_string(),
+    }
+}
+
+fn unmute(state: &AppState) -> String {
+    if !state.mute.is_muted() {
+        return "Not muted".to_string();
+    }
+    if let Err(e) = state.mute.unmute() {
+        error!("failed to save mute", error = e);
+        return format!("Failed to unmute: {}", e);
+    }
+    info!("unmuted");
+    match state.held.len() {
+        0 => "Unmuted".to_string(),
+        1 => "Unmuted; sending 1 held message".to_string(),
+        n => format!("Unmuted; sending {} held messages", n),
+    }
+}
+
+fn history(state: &AppState, arg: &str) -> String {
+    let n = match arg {
+        "" => 10,
+        a => match a.parse::<usize>() {
+            Ok(n) if n > 0 => n.min(MAX_HISTORY),
+            _ => return format!("Invalid count: {}. Try /history 10", arg),
+        },
+    };
+    let sent = state.activity.recent_sent(n);
+    if sent.is_empty() {
+        return "Nothing sent yet".to_string();
+    }
+    let mut out = format!("Last {} messages:", sent.len());
+    for e in sent.iter().rev() {
+        out.push_str(&format!(
+            "\n• {} → {}: {}",
+            time(e.at),
+            e.destination,
+            e.text
+        ));
+    }
+    out
+}
+
+/// Handle a `message` update received through the bot `token`.
+pub async fn on_message(state: &AppState, token: &str, message: &Value) {
+    let Some(text) = message["text"].as_str().filter(|t| t.starts_with('/')) else {
+        return;
+    };
+    let (command, arg) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    // In groups, commands can be addressed as `/status@relay_bot`.
+    let command = command.split('@').next().unwrap_or_default();
+    let arg = arg.trim();
+
+    let user = buttons::User::from_telegram(&message["from"]);
+    if !is_admin(state, &message["from"]) {
+        warn!(
+            "ignoring command from unauthorized user",
+            command = command,
+            user = user.display(),
+            user_id = user.id
+        );
+        return;
+    }
+    info!("command", command = command, user = user.display());
+
+    let reply = match command {
+        "/status" => status(state),
+        "/mute" => mute(state, arg),
+        "/unmute" => unmute(state),
+        "/history" => history(state, arg),
+        _ => help(),
+    };
+    let reply = split::truncate_message(&reply, None, split::MAX_MESSAGE_LEN);
+
+    let chat_id = message["chat"]["id"].as_i64().unwrap_or_default();
+    let body = serde_json::json!({
+        "chat_id": chat_id,
+        "text": reply,
+        "reply_parameters": {
+            "message_id": message["message_id"],
+            "allow_sending_without_reply": true,
+        },
+    });
+    if let Err(e) = call_api(state, token, "sendMessage", Some(chat_id), |r| {
+        r.json(&body)
+    })
+    .await
+    {
+        warn!("failed to answer command", command = command, error = e);
+    }
+}
+
+/// Register the commands in the bot's menu.
+pub async fn register(state: &AppState, token: &str) {
+    let commands: Vec<Value> = COMMANDS
+        .iter()
+        .map(|(command, description)| {
+            serde_json::json!({"command": command, "description": description})
+        })
+        .collect();
+    let body = serde_json::json!({"commands": commands});
+    if let Err(e) = call_api(state, token, "setMyCommands", None, |r| r.json(&body)).await {
+        warn!("failed to register bot commands", error = e);
+    }
+}
@@ -0,0 +1,413 @@
+//! The JSON config file.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::BoxError;
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Config {
+    pub listen_addr: String,
+    pub telegram_bot_token: String,
+    pub telegram_username: String,
+    #[serde(default)]
+    pub telegram_chat_id: Option<i64>,
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+    #[serde(default)]
+    pub telegram_api_base: Option<String>,
+    #[serde(default)]
+    pub data_dir: Option<String>,
+    #[serde(default)]
+    pub outbox: Option<OutboxConfig>,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Named recipients besides the default `telegram_username` one.
+    #[serde(default)]
+    pub destinations: BTreeMap<String, DestinationConfig>,
+    /// When non-empty, every route except `/health` requires one of these.
+    #[serde(default)]
+    pub api_keys: Vec<ApiKeyConfig>,
+    /// Webhook signature secrets keyed by route name. A signed route accepts
+    /// a valid signature instead of an API key.
+    #[serde(default)]
+    pub signatures: BTreeMap<String, SignatureConfig>,
+    #[serde(default)]
+    pub log_format: Option<LogFormat>,
+    #[serde(default)]
+    pub alertmanager: Option<AlertmanagerConfig>,
+    #[serde(default)]
+    pub github: Option<ForgeConfig>,
+    #[serde(default)]
+    pub gitea: Option<ForgeConfig>,
+    /// Message templates for `/hook/<name>`, keyed by name.
+    #[serde(default)]
+    pub templates: BTreeMap<String, TemplateConfig>,
+    /// Receive updates from Telegram, needed for callback buttons.
+    #[serde(default)]
+    pub updates: Option<UpdatesConfig>,
+    #[serde(default)]
+    pub status: Option<StatusConfig>,
+    #[serde(default)]
+    pub dedupe: Option<DedupeConfig>,
+    /// Digests for the default destination.
+    #[serde(default)]
+    pub batch: Option<BatchConfig>,
+    /// Quiet hours of the default destination.
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHoursConfig>,
+    /// Expected check-ins for `/heartbeat/<name>`, keyed by name.
+    #[serde(default)]
+    pub heartbeats: BTreeMap<String, HeartbeatConfig>,
+    /// Recurring messages, keyed by name.
+    #[serde(default)]
+    pub schedules: BTreeMap<String, ScheduleConfig>,
+}
+
+impl Config {
+    /// Every bot token, API key and signature secret, for log redaction.
+    pub fn secrets(&self) -> Vec<String> {
+        let mut secrets = vec![self.telegram_bot_token.clone()];
+        secrets.extend(
+            self.destinations
+                .values()
+                .filter_map(|d| d.bot_token.clone()),
+        );
+        secrets.extend(self.api_keys.iter().map(|k| k.key.clone()));
+        secrets.extend(self.signatures.values().map(|s| s.secret.clone()));
+        secrets
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// `message key=value ...`, for reading in a terminal.
+    #[default]
+    Text,
+    Logfmt,
+    Json,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ApiKeyConfig {
+    /// Label used in error messages; never the key itself.
+    pub name: String,
+    pub key: String,
+    /// Destinations this key may send to. Any if unset.
+    #[serde(default)]
+    pub destinations: Option<Vec<String>>,
+    /// Routes this key may use, e.g. `message` or `document`. Any if unset.
+    #[serde(default)]
+    pub routes: Option<Vec<String>>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DestinationConfig {
+    /// User, group or channel id. Resolved from `username` if unset.
+    #[serde(default)]
+    pub chat_id: Option<i64>,
+    /// Username to wait for a `/start` from, like `telegram_username`.
+    #[serde(default)]
+    pub username: Option<String>,
+    /// Send through a different bot than `telegram_bot_token`.
+    #[serde(default)]
+    pub bot_token: Option<String>,
+    /// Collect messages into digests instead of sending each one.
+    #[serde(default)]
+    pub batch: Option<BatchConfig>,
+    /// When to stop ringing; see [`QuietHoursConfig`].
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHoursConfig>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct QuietHoursConfig {
+    /// Start of the quiet window, `HH:MM`.
+    pub start: String,
+    /// End of the quiet window, `HH:MM`; before `start` for a window
+    /// that spans midnight.
+    pub end: String,
+    /// IANA time zone name, e.g. `Europe/Berlin`. UTC if unset.
+    #[serde(default)]
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub mode: QuietMode,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuietMode {
+    /// Send right away, without a notification sound.
+    #[default]
+    Silent,
+    /// Keep messages until the window ends, then send them.
+    Hold,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct BatchConfig {
+    /// How long a batch collects messages, from its first one.
+    #[serde(default = "default_batch_window_secs")]
+    pub window_secs: u64,
+    /// Send a batch early once it holds this many messages.
+    #[serde(default = "default_batch_max_messages")]
+    pub max_messages: usize,
+    /// Digest header; `Digest` if unset.
+    #[serde(default)]
+    pub title: Option<String>,
+}
+
+fn default_batch_window_secs() -> u64 {
+    60
+}
+
+fn default_batch_max_messages() -> usize {
+    50
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct SignatureConfig {
+    pub secret: String,
+    #[serde(default)]
+    pub scheme: SignatureScheme,
+    /// Header carrying the signature, if not the scheme's usual one.
+    #[serde(default)]
+    pub header: Option<String>,
+    /// How far a `timestamped` signature may be from our clock.
+    #[serde(default = "default_tolerance_secs")]
+    pub tolerance_secs: u64,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureScheme {
+    #[default]
+    Github,
+    Generic,
+    Timestamped,
+}
+
+fn default_tolerance_secs() -> u64 {
+    300
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct AlertmanagerConfig {
+    /// minijinja template rendering the webhook payload. Built-in if unset.
+    #[serde(default)]
+    pub template: Option<String>,
+    /// `html` (default), `markdown` or `plain`.
+    #[serde(default)]
+    pub parse_mode: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct TemplateConfig {
+    /// minijinja template rendered with the request's JSON body.
+    pub template: String,
+    /// `html`, `markdown` or `plain` (default).
+    #[serde(default)]
+    pub parse_mode: Option<String>,
+}
+
+/// Settings for the `/github` and `/gitea` webhook routes.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ForgeConfig {
+    /// Events to send, as `push` (every action) or `pull_request.opened`.
+    /// A built-in selection if unset.
+    #[serde(default)]
+    pub events: Option<Vec<String>>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct UpdatesConfig {
+    /// How long each `getUpdates` call waits for new updates.
+    #[serde(default = "default_poll_timeout_secs")]
+    pub poll_timeout_secs: u64,
+    /// Telegram user ids or usernames allowed to use bot commands and,
+    /// if set, the only ones allowed to answer callback buttons.
+    #[serde(default)]
+    pub admins: Vec<String>,
+}
+
+fn default_poll_timeout_secs() -> u64 {
+    30
+}
+
+/// Settings for `/status/<key>` messages.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct StatusConfig {
+    /// Edits of one status message closer together than this are merged.
+    #[serde(default = "default_min_edit_interval_secs")]
+    pub min_edit_interval_secs: u64,
+}
+
+impl Default for StatusConfig {
+    fn default() -> Self {
+        StatusConfig {
+            min_edit_interval_secs: default_min_edit_interval_secs(),
+        }
+    }
+}
+
+fn default_min_edit_interval_secs() -> u64 {
+    3
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DedupeConfig {
+    /// How long after a message its repeats are dropped.
+    #[serde(default = "default_dedupe_window_secs")]
+    pub window_secs: u64,
+    /// Also drop messages with the same text, not just the same key.
+    #[serde(default = "default_true")]
+    pub identical: bool,
+    /// Edit "(repeated N×)" into the first message.
+    #[serde(default)]
+    pub count_repeats: bool,
+}
+
+impl Default for DedupeConfig {
+    fn default() -> Self {
+        DedupeConfig {
+            window_secs: default_dedupe_window_secs(),
+            identical: true,
+            count_repeats: false,
+        }
+    }
+}
+
+fn default_dedupe_window_secs() -> u64 {
+    300
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct OutboxConfig {
+    /// Give up on a message after this many failed attempts. Unlimited if unset.
+    #[serde(default)]
+    pub max_attempts: Option<u32>,
+    #[serde(default = "default_initial_backoff_secs")]
+    pub initial_backoff_secs: u64,
+    #[serde(default = "default_max_backoff_secs")]
+    pub max_backoff_secs: u64,
+}
+
+fn default_initial_backoff_secs() -> u64 {
+    2
+}
+
+fn default_max_backoff_secs() -> u64 {
+    300
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct RateLimitConfig {
+    /// Messages per second for each bot.
+    #[serde(default = "default_global_per_sec")]
+    pub global_per_sec: f64,
+    #[serde(default = "default_chat_per_sec")]
+    pub chat_per_sec: f64,
+    #[serde(default = "default_group_per_min")]
+    pub group_per_min: f64,
+    /// Longest `retry_after` we sleep through before giving up on a 429.
+    #[serde(default = "default_max_retry_after_secs")]
+    pub max_retry_after_secs: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            global_per_sec: default_global_per_sec(),
+            chat_per_sec: default_chat_per_sec(),
+            group_per_min: default_group_per_min(),
+            max_retry_after_secs: default_max_retry_after_secs(),
+        }
+    }
+}
+
+fn default_global_per_sec() -> f64 {
+    30.0
+}
+
+fn default_chat_per_sec() -> f64 {
+    1.0
+}
+
+fn default_group_per_min() -> f64 {
+    20.0
+}
+
+fn default_max_retry_after_secs() -> u64 {
+    60
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct HeartbeatConfig {
+    /// How often the job checks in.
+    pub interval_secs: u64,
+    /// How late a check-in may be before it counts as missed.
+    #[serde(default = "default_grace_secs")]
+    pub grace_secs: u64,
+    /// Where alerts go; the default destination if unset.
+    #[serde(default)]
+    pub destination: Option<String>,
+}
+
+fn default_grace_secs() -> u64 {
+    60
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ScheduleConfig {
+    /// When to send, as a cron expression (see [`crate::cron`]).
+    pub cron: String,
+    /// IANA time zone name the expression is read in. UTC if unset.
+    #[serde(default)]
+    pub timezone: Option<String>,
+    /// Text to send. Set this or `template`.
+    #[serde(default)]
+    pub message: Option<String>,
+    /// `html`, `markdown` or `plain` (default), for `message`.
+    #[serde(default)]
+    pub parse_mode: Option<String>,
+    /// Name of a `templates` entry to render instead of `message`.
+    #[serde(default)]
+    pub template: Option<String>,
+    /// Where to send; the default destination if unset.
+    #[serde(default)]
+    pub destination: Option<String>,
+    /// What to do about runs missed while the relay was down.
+    #[serde(default)]
+    pub missed: MissedRuns,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissedRuns {
+    #[default]
+    Skip,
+    /// Send once for the latest missed run, right after startup.
+    CatchUp,
+}
+
+pub fn load_config(path: &PathBuf) -> Result<Config, BoxError> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read config file {}: {}", path.display(), e))?;
+    let config: Config = serde_json::from_str(&contents)
+        .map_err(|e| format!("failed to parse config file {}: {}", path.display(), e))?;
+    Ok(config)
+}
+
+pub fn save_config(path: &PathBuf, config: &Config) -> Result<(), BoxError> {
+    let contents = serde_json::to_string_pretty(config)?;
+    std::fs::write(path, contents.as_bytes())
+        .map_err(|e| format!("failed to write config file {}: {}", path.display(), e))?;
+    Ok(())
+}
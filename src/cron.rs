@@ -0,0 +1,384 @@
+//! Cron expressions: `minute hour day-of-month month day-of-week`, with
+//! `*`, lists, ranges, steps (`*/15`, `1-5/2`), month and weekday names,
+//! and the `@hourly`, `@daily`, `@weekly`, `@monthly` and `@yearly`
+//! shorthands. Times are matched in a time zone.
+
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, TimeDelta, Timelike, Utc};
+use chrono_tz::Tz;
+
+/// How far ahead [`Cron::next_after`] looks before giving up, e.g. on
+/// `0 0 30 2 *`.
+const SEARCH_YEARS: i32 = 5;
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+pub struct Cron {
+    /// Bit `n` is set if value `n` matches.
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    /// Sunday is 0.
+    weekdays: u64,
+    /// Whether day-of-month and day-of-week start with `*`. If both are
+    /// restricted, a day matching either one matches, as in cron.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    pub fn parse(expr: &str) -> Result<Cron, String> {
+        let expr = match expr.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            e => e,
+        };
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!(
+                "expected 5 fields (minute hour day month weekday), got {}",
+                fields.len()
+            ));
+        };
+        let weekdays = field(weekday, 0, 7, &WEEKDAYS, 0).map_err(|e| format!("weekday: {}", e))?;
+        Ok(Cron {
+            minutes: field(minute, 0, 59, &[], 0).map_err(|e| format!("minute: {}", e))?,
+            hours: field(hour, 0, 23, &[], 0).map_err(|e| format!("hour: {}", e))?,
+            days: field(day, 1, 31, &[], 0).map_err(|e| format!("day: {}", e))?,
+            months: field(month, 1, 12, &MONTHS, 1).map_err(|e| format!("month: {}", e))?,
+            // 7 is Sunday too.
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            any_day: day.starts_with('*'),
+            any_weekday: weekday.starts_with('*'),
+        })
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day = bit(self.days, date.day());
+        let weekday = bit(self.weekdays, date.weekday().num_days_from_sunday());
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// The first time after `after` that matches in `tz`. Local times that
+    /// don't exist (skipped by a DST change) never match; ones that occur
+    /// twice match the first time.
+    pub fn next_after(&self, after: DateTime<Utc>, tz: Tz) -> Option<DateTime<Utc>> {
+        let start = after.with_timezone(&tz).naive_local();
+        let mut t = start.with_second(0)?.with_nanosecond(0)? + TimeDelta::minutes(1);
+        let limit = start.year() + SEARCH_YEARS;
+
+        while t.year() <= limit {
+            if !bit(self.months, t.month()) {
+                let (year, month) = match t.month() {
+                    12 => (t.year() + 1, 1),
+                    m => (t.year(), m + 1),
+                };
+                t = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+            } else if !self.matches_day(t.date()) {
+                t = (t.date() + TimeDelta::days(1)).and_hms_opt(0, 0, 0)?;
+            } else if !bit(self.hours, t.hour()) {
+                t = start_of_hour(t) + TimeDelta::hours(1);
+            } else if !bit(self.minutes, t.minute()) {
+                t += TimeDelta::minutes(1);
+            } else {
+                if let Some(at) = t.and_local_timezone(tz).earliest() {
+                    let at = at.to_utc();
+                    if at > after {
+                        return Some(at);
+                    }
+                }
+                t += TimeDelta::minutes(1);
+            }
+        }
+        None
+    }
+}
+
+fn start_of_hour(t: NaiveDateTime) -> NaiveDateTime {
+    t.date().and_hms_opt(t.hour(), 0, 0).unwrap_or(t)
+}
+
+fn bit(mask: u64, n: u32) -> bool {
+    mask & (1 << n) != 0
+}
+
+/// Parse one field into a bit mask of the values in `min..=max`. `names`
+/// are accepted for values from `first_name` up.
+fn field(spec: &str, min: u32, max: u32, names: &[&str], first_name: u32) -> Result<u64, String> {
+    let value = |s: &str| -> Result<u32, String> {
+        let lower = s.to_lowercase();
+        if let Some(i) = names.iter().position(|n| *n == lower) {
+            return Ok(first_name + i as u32);
+        }
+        match s.parse::<u32>() {
+            Ok(n) if (min..=max).contains(&n) => Ok(n),
+            _ => Err(format!("invalid value {:?}, expected {}-{}", s, min, max)),
+        }
+    };
+
+    let mut mask = 0u64;
+    for part in spec.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((r, s)) => match s.parse::<u32>() {
+                Ok(s) if s > 0 => (r, s),
+                _ => return Err(format!("invalid step in {:?}", part)),
+            },
+            None => (part, 1),
+        };
+        let (lo, hi) = if range == "*" {
+            (min, max)
+        } else if let Some((a, b)) = range.split_once('-') {
+            (value(a)?, value(b)?)
+        } else {
+            let n = value(range)?;
+            // `5/10` runs from 5 to the end, like `5-59/10`.
+            (n, if part.contains('/') { max } else { n })
+        };
+        if lo > hi {
+            return Err(format!("invalid range {:?}", range));
+        }
+        for n in (lo..=hi).step_by(step as usize) {
+            mask |= 1 << n;
+        }
+    }
+    Ok(mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M")
+            .unwrap()
+            .and_utc()
+    }
+
+    /// The first `n` times `expr` fires after `after`, in UTC.
+    fn runs(expr: &str, after: &str, n: usize) -> Vec<String> {
+        runs_in(expr, after, n, Tz::UTC)
+    }
+
+    fn runs_in(expr: &str, after: &str, n: usize, tz: Tz) -> Vec<String> {
+        let cron = Cron::parse(expr).unwrap();
+        let mut t = utc(after);
+        let mut out = Vec::new();
+        for _ in 0..n {
+            t = cron.next_after(t, tz).unwrap();
+            out.push(t.format("%Y-%m-%d %H:%M").to_string());
+        }
+        out
+    }
+
+    #[test]
+    fn next_minute_is_strictly_after() {
+        assert_eq!(
+            runs("* * * * *", "2026-10-15 12:00", 2),
+            ["2026-10-15 12:01", "2026-10-15 12:02"]
+        );
+        let cron = Cron::parse("30 12 * * *").unwrap();
+        let after = utc("2026-10-15 12:29") + TimeDelta::seconds(59);
+        assert_eq!(
+            cron.next_after(after, Tz::UTC),
+            Some(utc("2026-10-15 12:30"))
+        );
+    }
+
+    #[test]
+    fn lists_and_ranges() {
+        assert_eq!(
+            runs("0,30 9-10 * * *", "2026-10-15 08:00", 5),
+            [
+                "2026-10-15 09:00",
+                "2026-10-15 09:30",
+                "2026-10-15 10:00",
+                "2026-10-15 10:30",
+                "2026-10-16 09:00",
+            ]
+        );
+        // 2026-10-16 is a Friday.
+        assert_eq!(
+            runs("0 9 * * 1-5", "2026-10-16 10:00", 1),
+            ["2026-10-19 09:00"]
+        );
+    }
+
+    #[test]
+    fn steps() {
+        assert_eq!(
+            runs("*/20 12 * * *", "2026-10-15 11:00", 3),
+            ["2026-10-15 12:00", "2026-10-15 12:20", "2026-10-15 12:40"]
+        );
+        assert_eq!(
+            runs("5/20 12 * * *", "2026-10-15 11:00", 3),
+            ["2026-10-15 12:05", "2026-10-15 12:25", "2026-10-15 12:45"]
+        );
+        assert_eq!(
+            runs("0 1-10/3 * * *", "2026-10-15 00:00", 4),
+            [
+                "2026-10-15 01:00",
+                "2026-10-15 04:00",
+                "2026-10-15 07:00",
+                "2026-10-15 10:00",
+            ]
+        );
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(
+            runs("0 0 1 JAN,jul *", "2026-10-15 00:00", 2),
+            ["2027-01-01 00:00", "2027-07-01 00:00"]
+        );
+        assert_eq!(
+            runs("0 12 * * Mon-wed", "2026-10-15 00:00", 3),
+            ["2026-10-19 12:00", "2026-10-20 12:00", "2026-10-21 12:00"]
+        );
+    }
+
+    #[test]
+    fn seven_is_sunday() {
+        let sunday = ["2026-10-18 00:00", "2026-10-25 00:00"];
+        assert_eq!(runs("0 0 * * 7", "2026-10-15 00:00", 2), sunday);
+        assert_eq!(runs("0 0 * * 0", "2026-10-15 00:00", 2), sunday);
+        assert_eq!(runs("0 0 * * sun", "2026-10-15 00:00", 2), sunday);
+        assert_eq!(
+            runs("0 0 * * 6-7", "2026-10-15 00:00", 2),
+            ["2026-10-17 00:00", "2026-10-18 00:00"]
+        );
+    }
+
+    #[test]
+    fn day_of_month_or_day_of_week() {
+        // Both restricted: the 13th or any Friday.
+        assert_eq!(
+            runs("0 0 13 * fri", "2026-10-15 00:00", 4),
+            [
+                "2026-10-16 00:00",
+                "2026-10-23 00:00",
+                "2026-10-30 00:00",
+                "2026-11-06 00:00",
+            ]
+        );
+        assert_eq!(
+            runs("0 0 13 * fri", "2026-11-07 00:00", 2),
+            ["2026-11-13 00:00", "2026-11-20 00:00"]
+        );
+        // Only one restricted: that one alone decides.
+        assert_eq!(
+            runs("0 0 13 * *", "2026-10-15 00:00", 1),
+            ["2026-11-13 00:00"]
+        );
+        assert_eq!(
+            runs("0 0 * * fri", "2026-10-15 00:00", 1),
+            ["2026-10-16 00:00"]
+        );
+        // A step starting with `*` counts as unrestricted, as in cron.
+        assert_eq!(
+            runs("0 0 */10 * fri", "2026-10-15 00:00", 1),
+            ["2026-12-11 00:00"]
+        );
+    }
+
+    #[test]
+    fn shorthands() {
+        assert_eq!(runs("@hourly", "2026-10-15 12:30", 1), ["2026-10-15 13:00"]);
+        assert_eq!(runs("@daily", "2026-10-15 12:30", 1), ["2026-10-16 00:00"]);
+        assert_eq!(runs("@weekly", "2026-10-15 12:30", 1), ["2026-10-18 00:00"]);
+        assert_eq!(
+            runs("@monthly", "2026-10-15 12:30", 1),
+            ["2026-11-01 00:00"]
+        );
+        assert_eq!(runs("@yearly", "2026-10-15 12:30", 1), ["2027-01-01 00:00"]);
+    }
+
+    #[test]
+    fn time_zone() {
+        let tz: Tz = "America/New_York".parse().unwrap();
+        assert_eq!(
+            runs_in("0 9 * * *", "2026-10-15 00:00", 1, tz),
+            ["2026-10-15 13:00"]
+        );
+        // Past New York's 9:00, but before the next UTC 9:00.
+        assert_eq!(
+            runs_in("0 9 * * *", "2026-10-15 14:00", 1, tz),
+            ["2026-10-16 13:00"]
+        );
+    }
+
+    #[test]
+    fn dst_gap_is_skipped() {
+        // Berlin skips from 02:00 to 03:00 on 2026-03-29.
+        let tz: Tz = "Europe/Berlin".parse().unwrap();
+        assert_eq!(
+            runs_in("30 2 * * *", "2026-03-27 12:00", 3, tz),
+            ["2026-03-28 01:30", "2026-03-30 00:30", "2026-03-31 00:30"]
+        );
+        assert_eq!(
+            runs_in("0 * * * *", "2026-03-29 00:00", 2, tz),
+            ["2026-03-29 01:00", "2026-03-29 02:00"]
+        );
+    }
+
+    #[test]
+    fn dst_overlap_fires_once() {
+        // Berlin repeats 02:00 to 03:00 on 2026-10-25.
+        let tz: Tz = "Europe/Berlin".parse().unwrap();
+        assert_eq!(
+            runs_in("30 2 * * *", "2026-10-24 12:00", 2, tz),
+            ["2026-10-25 00:30", "2026-10-26 01:30"]
+        );
+        assert_eq!(
+            runs_in("0 * * * *", "2026-10-24 23:30", 3, tz),
+            ["2026-10-25 00:00", "2026-10-25 02:00", "2026-10-25 03:00"]
+        );
+    }
+
+    #[test]
+    fn never_fires() {
+        let after = utc("2026-10-15 00:00");
+        for expr in ["0 0 30 2 *", "0 0 31 4,6,9,11 *"] {
+            let cron = Cron::parse(expr).unwrap();
+            assert_eq!(cron.next_after(after, Tz::UTC), None, "{}", expr);
+        }
+        // Leap days are found within the search window.
+        assert_eq!(
+            runs("0 0 29 2 *", "2026-10-15 00:00", 1),
+            ["2028-02-29 00:00"]
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        for expr in [
+            "",
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "* * * foo *",
+            "* * * * mon-",
+            "1,,2 * * * *",
+        ] {
+            assert!(Cron::parse(expr).is_err(), "{:?}", expr);
+        }
+        assert_eq!(
+            Cron::parse("61 * * * *").err().unwrap(),
+            "minute: invalid value \"61\", expected 0-59"
+        );
+    }
+}
@@ -0,0 +1,184 @@
+//! Collapsing repeated messages. A message with the same `dedupe_key`,
+//! or with `identical` on the same text, as one sent to the same
+//! destination within the window is dropped. With `count_repeats`, the
+//! first message is edited to say how often it was repeated.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::DedupeConfig;
+use crate::telegram::{edit_message_text, MessageOptions};
+use crate::template::escape;
+use crate::{split, AppState};
+
+/// How often repeat counters are brought up to date.
+const COUNTER_TICK: Duration = Duration::from_secs(2);
+
+/// What was sent first within a window.
+struct Seen {
+    first_at: Instant,
+    /// Repeats dropped so far.
+    repeats: u32,
+    /// Repeats the message currently shows.
+    shown: u32,
+    /// Set once sent, if the message can be edited: sent right away, as
+    /// a single part.
+    message: Option<Sent>,
+}
+
+/// A sent message, as needed to edit a counter into it.
+struct Sent {
+    message_id: i64,
+    text: String,
+    options: MessageOptions,
+}
+
+type Key = (String, String);
+
+pub struct Deduper {
+    window: Duration,
+    identical: bool,
+    count_repeats: bool,
+    seen: Mutex<HashMap<Key, Seen>>,
+}
+
+/// A repeat of a message sent earlier in the window.
+pub struct Repeat {
+    pub repeats: u32,
+    pub message_id: Option<i64>,
+}
+
+impl Deduper {
+    /// Keyed deduplication always works; identical texts are only
+    /// collapsed when `dedupe` is configured.
+    pub fn new(config: Option<&DedupeConfig>) -> Deduper {
+        let default = DedupeConfig::default();
+        let c = config.unwrap_or(&default);
+        Deduper {
+            window: Duration::from_secs(c.window_secs),
+            identical: config.is_some() && c.identical,
+            count_repeats: c.count_repeats,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The key `text` is deduplicated by, if any.
+    pub fn key(&self, text: &str, options: &MessageOptions) -> Option<String> {
+        if let Some(key) = &options.dedupe_key {
+            return Some(format!("key:{}", key));
+        }
+        if !self.identical {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        text.hash(&mut hasher);
+        options.parse_mode.hash(&mut hasher);
+        Some(format!("text:{:016x}", hasher.finish()))
+    }
+
+    /// Record a message about to be sent, or count it as a repeat if one
+    /// with the same key was sent within the window.
+    pub fn check(&self, destination: &str, key: &str) -> Option<Repeat> {
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, s| s.first_at.elapsed() < self.window);
+        let k = (destination.to_string(), key.to_string());
+        if let Some(s) = seen.get_mut(&k) {
+            s.repeats += 1;
+            return Some(Repeat {
+                repeats: s.repeats,
+                message_id: s.message.as_ref().map(|m| m.message_id),
+            });
+        }
+        seen.insert(
+            k,
+            Seen {
+                first_at: Instant::now(),
+                repeats: 0,
+                shown: 0,
+                message: None,
+            },
+        );
+        None
+    }
+
+    /// The message recorded by [`check`](Self::check) was sent as a
+    /// single message that can be edited.
+    pub fn sent(
+        &self,
+        destination: &str,
+        key: &str,
+        message_id: i64,
+        text: &str,
+        options: &MessageOptions,
+    ) {
+        let mut seen = self.seen.lock().unwrap();
+        if let Some(s) = seen.get_mut(&(destination.to_string(), key.to_string())) {
+            s.message = Some(Sent {
+                message_id,
+                text: text.to_string(),
+                options: options.clone(),
+            });
+        }
+    }
+
+    /// The message recorded by [`check`](Self::check) couldn't be sent, so
+    /// the next one with its key isn't a repeat.
+    pub fn failed(&self, destination: &str, key: &str) {
+        let mut seen = self.seen.lock().unwrap();
+        seen.remove(&(destination.to_string(), key.to_string()));
+    }
+}
+
+/// Edit repeat counters into first messages. Counters are updated in
+/// batches so a burst of repeats costs one edit, not one per repeat.
+pub async fn run_counter(state: Arc<AppState>) {
+    let deduper = &state.deduper;
+    if !deduper.count_repeats {
+        return;
+    }
+    loop {
+        tokio::time::sleep(COUNTER_TICK).await;
+
+        let due: Vec<(String, i64, u32, String, MessageOptions)> = {
+            let mut seen = deduper.seen.lock().unwrap();
+            seen.iter_mut()
+                .filter(|(_, s)| s.repeats > s.shown)
+                .filter_map(|((dest, _), s)| {
+                    let m = s.message.as_ref()?;
+                    s.shown = s.repeats;
+                    Some((
+                        dest.clone(),
+                        m.message_id,
+                        s.repeats,
+                        m.text.clone(),
+                        m.options.clone(),
+                    ))
+                })
+                .collect()
+        };
+
+        for (dest_name, message_id, repeats, text, options) in due {
+            let Some(dest) = state.destination(Some(&dest_name)) else {
+                continue;
+            };
+            let parse_mode = options.parse_mode.as_deref();
+            let note = escape(&format!("(repeated {}×)", repeats), parse_mode);
+            let limit = split::MAX_MESSAGE_LEN - split::utf16_len(&note) - 2;
+            let text = format!(
+                "{}\n\n{}",
+                split::truncate_message(&text, parse_mode, limit),
+                note
+            );
+            if let Err(e) = edit_message_text(&state, &dest, message_id, &text, &options).await {
+                warn!(
+                    "failed to update repeat counter",
+                    message_id = message_id,
+                    error = e
+                );
+            }
+        }
+    }
+}
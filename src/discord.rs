@@ -0,0 +1,98 @@
+//! `POST /discord`: Discord webhook compatibility. `content` and `embeds`
+//! are rendered into one Telegram HTML message, with Discord markdown
+//! converted along the way.
+
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::{Request, Response, StatusCode};
+use serde_json::Value;
+
+use crate::markup::{to_html, Dialect};
+use crate::telegram::{Destination, MessageOptions};
+use crate::util::{h, link, str_at};
+use crate::{deliver_message, json_response, overflow_header, AppState, BoxError};
+
+fn md(s: &str) -> String {
+    to_html(s, Dialect::Discord)
+}
+
+fn render_embed(e: &Value) -> String {
+    let mut lines = Vec::new();
+    let author = str_at(e, "/author/name");
+    if !author.is_empty() {
+        lines.push(format!("<i>{}</i>", h(author)));
+    }
+    let title = str_at(e, "/title");
+    if !title.is_empty() {
+        let title = match str_at(e, "/url") {
+            "" => md(title),
+            url => format!("<a href=\"{}\">{}</a>", h(url), md(title)),
+        };
+        lines.push(format!("<b>{}</b>", title));
+    }
+    let description = str_at(e, "/description");
+    if !description.is_empty() {
+        lines.push(md(description));
+    }
+    for field in e["fields"].as_array().into_iter().flatten() {
+        lines.push(format!(
+            "<b>{}</b>\n{}",
+            md(str_at(field, "/name")),
+            md(str_at(field, "/value"))
+        ));
+    }
+    if let Some(url) = e["image"]["url"].as_str() {
+        lines.push(link(url, "image"));
+    }
+    let footer = str_at(e, "/footer/text");
+    if !footer.is_empty() {
+        lines.push(format!("<i>{}</i>", h(footer)));
+    }
+    lines.join("\n")
+}
+
+fn render(payload: &Value) -> String {
+    let mut sections = Vec::new();
+    let content = str_at(payload, "/content");
+    if !content.is_empty() {
+        sections.push(md(content));
+    }
+    for e in payload["embeds"].as_array().into_iter().flatten() {
+        sections.push(render_embed(e));
+    }
+    sections
+        .into_iter()
+        .filter(|s| !s.trim().is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+pub async fn handle(
+    req: Request<Bytes>,
+    state: &AppState,
+    dest: &Destination,
+) -> Result<Response<Full<Bytes>>, BoxError> {
+    let overflow = overflow_header(req.headers());
+    let payload: Value = match serde_json::from_slice(req.body()) {
+        Ok(v) => v,
+        Err(e) => {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                serde_json::json!({"error": format!("invalid JSON: {}", e)}),
+            );
+        }
+    };
+
+    let message = render(&payload);
+    if message.trim().is_empty() {
+        return json_response(
+            StatusCode::BAD_REQUEST,
+            serde_json::json!({"error": "cannot send an empty message"}),
+        );
+    }
+    let options = MessageOptions {
+        parse_mode: Some("HTML".to_string()),
+        ..Default::default()
+    };
+    deliver_message(state, dest, message, options, overflow).await
+}
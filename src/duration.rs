@@ -0,0 +1,124 @@
+//! Durations as people write them: `90` (seconds), `90s`, `5m`, `2h`,
+//! `1d` or combinations like `1h30m`.
+
+use std::time::Duration;
+
+pub fn parse(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    if let Ok(secs) = s.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let mut total = 0u64;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+        if digits == 0 {
+            return None;
+        }
+        let n: u64 = rest[..digits].parse().ok()?;
+        let unit = match rest.as_bytes()[digits] {
+            b's' => 1,
+            b'm' => 60,
+            b'h' => 3600,
+            b'd' => 86400,
+            _ => return None,
+        };
+        total = total.checked_add(n.checked_mul(unit)?)?;
+        rest = &rest[digits + 1..];
+    }
+    Some(Duration::from_secs(total)).filter(|_| !s.is_empty())
+}
+
+/// Format `d` the way [`parse`] reads it, rounded down to the largest two
+/// units: `45s`, `5m30s`, `2h`, `1d3h`.
+pub fn format(d: Duration) -> String {
+    const UNITS: [(u64, char); 4] = [(86400, 'd'), (3600, 'h'), (60, 'm'), (1, 's')];
+    let secs = d.as_secs();
+    let Some(i) = UNITS.iter().position(|&(size, _)| secs >= size) else {
+        return "0s".to_string();
+    };
+    let (size, unit) = UNITS[i];
+    let mut out = format!("{}{}", secs / size, unit);
+    if let Some(&(next, next_unit)) = UNITS.get(i + 1) {
+        let n = secs % size / next;
+        if n > 0 {
+            out.push_str(&format!("{}{}", n, next_unit));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(n: u64) -> Option<Duration> {
+        Some(Duration::from_secs(n))
+    }
+
+    #[test]
+    fn parse_units() {
+        assert_eq!(parse("90"), secs(90));
+        assert_eq!(parse("0"), secs(0));
+        assert_eq!(parse("90s"), secs(90));
+        assert_eq!(parse("5m"), secs(300));
+        assert_eq!(parse("2h"), secs(7200));
+        assert_eq!(parse("1d"), secs(86400));
+        assert_eq!(parse(" 15m "), secs(900));
+    }
+
+    #[test]
+    fn parse_combinations() {
+        assert_eq!(parse("1h30m"), secs(5400));
+        assert_eq!(parse("1d2h3m4s"), secs(93784));
+        // Units may repeat or come in any order.
+        assert_eq!(parse("30m1h"), secs(5400));
+        assert_eq!(parse("1m1m"), secs(120));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        for s in [
+            "", " ", "m", "1.5h", "-5m", "5x", "5 m", "1h 30m", "h1", "5mm", "1h30", "1w", "5é",
+        ] {
+            assert_eq!(parse(s), None, "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn parse_overflow() {
+        assert_eq!(parse("99999999999999999999"), None);
+        assert_eq!(parse("999999999999999999d"), None);
+        assert_eq!(parse(&format!("{}s1s", u64::MAX)), None);
+    }
+
+    #[test]
+    fn format_units() {
+        assert_eq!(format(Duration::ZERO), "0s");
+        assert_eq!(format(Duration::from_millis(999)), "0s");
+        assert_eq!(format(Duration::from_secs(45)), "45s");
+        assert_eq!(format(Duration::from_secs(60)), "1m");
+        assert_eq!(format(Duration::from_secs(330)), "5m30s");
+        assert_eq!(format(Duration::from_secs(7200)), "2h");
+        assert_eq!(format(Duration::from_secs(86400)), "1d");
+    }
+
+    #[test]
+    fn format_keeps_two_units() {
+        // 1d3h5m7s: the minutes and seconds are dropped.
+        assert_eq!(format(Duration::from_secs(97507)), "1d3h");
+        // Skipped units aren't filled in from smaller ones.
+        assert_eq!(format(Duration::from_secs(86400 + 59)), "1d");
+        assert_eq!(format(Duration::from_secs(3600 + 59)), "1h");
+    }
+
+    #[test]
+    fn format_round_trips() {
+        for n in [0, 1, 59, 60, 61, 3599, 3600, 3660, 86400, 90000] {
+            let d = Duration::from_secs(n);
+            let s = format(d);
+            // Exact when the duration fits in two units.
+            assert_eq!(parse(&s), Some(d), "{}", s);
+        }
+    }
+}
@@ -0,0 +1,317 @@
+//! `POST /github` and `POST /gitea`: repository webhooks from GitHub and
+//! from Gitea or Forgejo, whose payloads follow GitHub's closely enough to
+//! share the rendering.
+
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::{HeaderMap, Request, Response, StatusCode};
+use serde_json::Value;
+
+use crate::config::ForgeConfig;
+use crate::telegram::{Destination, MessageOptions};
+use crate::util::{h, link, str_at};
+use crate::{deliver_message, json_response, overflow_header, AppState, BoxError};
+
+/// Events sent when a config sets no `events` of its own.
+pub const DEFAULT_EVENTS: &[&str] = &[
+    "push",
+    "pull_request.opened",
+    "pull_request.closed",
+    "pull_request.reopened",
+    "pull_request.ready_for_review",
+    "workflow_run.completed",
+    "release.published",
+    "issues.opened",
+    "issues.closed",
+    "issues.reopened",
+    "check_suite.completed",
+];
+
+/// Commits listed in a push message before "... and N more".
+const MAX_PUSH_COMMITS: usize = 5;
+
+#[derive(Clone, Copy)]
+pub enum Flavor {
+    Github,
+    Gitea,
+}
+
+impl Flavor {
+    fn event_headers(self) -> &'static [&'static str] {
+        match self {
+            Flavor::Github => &["x-github-event"],
+            // Forgejo sends both its own header and Gitea's.
+            Flavor::Gitea => &["x-forgejo-event", "x-gitea-event", "x-github-event"],
+        }
+    }
+}
+
+fn event_name(headers: &HeaderMap, flavor: Flavor) -> Option<String> {
+    flavor
+        .event_headers()
+        .iter()
+        .find_map(|h| headers.get(*h))
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_lowercase())
+}
+
+/// Whether `event` with `action` is in the allowlist. An entry is either a
+/// bare event name, allowing every action, or `event.action`.
+fn allowed(config: Option<&ForgeConfig>, event: &str, action: &str) -> bool {
+    let qualified = format!("{}.{}", event, action);
+    match config.and_then(|c| c.events.as_ref()) {
+        Some(events) => events.iter().any(|e| *e == event || *e == qualified),
+        None => DEFAULT_EVENTS
+            .iter()
+            .any(|e| *e == event || *e == qualified),
+    }
+}
+
+fn short_sha(sha: &str) -> &str {
+    sha.get(..7).unwrap_or(sha)
+}
+
+fn first_line(s: &str) -> &str {
+    s.lines().next().unwrap_or("").trim()
+}
+
+fn sender(p: &Value) -> &str {
+    [
+        "/sender/login",
+        "/sender/username",
+        "/pusher/login",
+        "/pusher/username",
+        "/pusher/name",
+    ]
+    .iter()
+    .map(|ptr| str_at(p, ptr))
+    .find(|s| !s.is_empty())
+    .unwrap_or("someone")
+}
+
+fn repo(p: &Value) -> String {
+    link(
+        str_at(p, "/repository/html_url"),
+        str_at(p, "/repository/full_name"),
+    )
+}
+
+fn conclusion_icon(conclusion: &str) -> &'static str {
+    match conclusion {
+        "success" => "✅",
+        "failure" | "timed_out" | "startup_failure" => "❌",
+        "cancelled" | "skipped" | "neutral" | "stale" => "⚪",
+        _ => "⏳",
+    }
+}
+
+fn render_push(p: &Value) -> Option<String> {
+    let full_ref = str_at(p, "/ref");
+    let (kind, name) = match full_ref.strip_prefix("refs/tags/") {
+        Some(tag) => ("tag", tag),
+        None => (
+            "branch",
+            full_ref.strip_prefix("refs/heads/").unwrap_or(full_ref),
+        ),
+    };
+    let who = h(sender(p));
+    let target = format!("{}:<code>{}</code>", repo(p), h(name));
+
+    if p["deleted"].as_bool() == Some(true) {
+        return Some(format!("🗑 {} deleted {} {}", who, kind, target));
+    }
+    let commits = p["commits"].as_array().map(Vec::as_slice).unwrap_or(&[]);
+    if kind == "tag" || commits.is_empty() {
+        if p["created"].as_bool() == Some(true) {
+            return Some(format!("🏷 {} created {} {}", who, kind, target));
+        }
+        return None;
+    }
+
+    let compare = [str_at(p, "/compare"), str_at(p, "/compare_url")]
+        .into_iter()
+        .find(|s| !s.is_empty())
+        .unwrap_or("");
+    let count = commits.len();
+    let mut out = format!(
+        "📦 {} {}pushed {} to {}",
+        who,
+        if p["forced"].as_bool() == Some(true) {
+            "force-"
+        } else {
+            ""
+        },
+        link(
+            compare,
+            &format!("{} commit{}", count, if count == 1 { "" } else { "s" })
+        ),
+        target
+    );
+    for c in commits.iter().take(MAX_PUSH_COMMITS) {
+        out.push_str(&format!(
+            "\n• {} {}",
+            link(str_at(c, "/url"), short_sha(str_at(c, "/id"))),
+            h(first_line(str_at(c, "/message")))
+        ));
+    }
+    if count > MAX_PUSH_COMMITS {
+        out.push_str(&format!("\n… and {} more", count - MAX_PUSH_COMMITS));
+    }
+    Some(out)
+}
+
+fn render_pull_request(p: &Value, action: &str) -> String {
+    let pr = &p["pull_request"];
+    let (icon, verb) = match action {
+        "closed" if pr["merged"].as_bool() == Some(true) => ("🟣", "merged"),
+        "closed" => ("🔴", "closed"),
+        "opened" | "reopened" => ("🟢", action),
+        "ready_for_review" => ("🟢", "marked ready for review"),
+        _ => ("🔀", action),
+    };
+    let number = pr["number"].as_u64().or(p["number"].as_u64()).unwrap_or(0);
+    format!(
+        "{} {} {} pull request {} in {}\n<b>{}</b>",
+        icon,
+        h(sender(p)),
+        h(verb),
+        link(str_at(pr, "/html_url"), &format!("#{}", number)),
+        repo(p),
+        h(str_at(pr, "/title"))
+    )
+}
+
+fn render_issue(p: &Value, action: &str) -> String {
+    let issue = &p["issue"];
+    let icon = match action {
+        "opened" | "reopened" => "🐛",
+        "closed" => "✔️",
+        _ => "📝",
+    };
+    format!(
+        "{} {} {} issue {} in {}\n<b>{}</b>",
+        icon,
+        h(sender(p)),
+        h(action),
+        link(
+            str_at(issue, "/html_url"),
+            &format!("#{}", issue["number"].as_u64().unwrap_or(0))
+        ),
+        repo(p),
+        h(str_at(issue, "/title"))
+    )
+}
+
+fn render_release(p: &Value, action: &str) -> String {
+    let release = &p["release"];
+    let tag = str_at(release, "/tag_name");
+    let name = match str_at(release, "/name") {
+        "" => tag,
+        n => n,
+    };
+    format!(
+        "🚀 Release {} {} in {}",
+        link(str_at(release, "/html_url"), name),
+        h(action),
+        repo(p)
+    )
+}
+
+fn render_workflow_run(p: &Value) -> String {
+    let run = &p["workflow_run"];
+    let conclusion = str_at(run, "/conclusion");
+    let status = match conclusion {
+        "" => str_at(run, "/status"),
+        c => c,
+    };
+    format!(
+        "{} Workflow {} {} on {}:<code>{}</code>",
+        conclusion_icon(conclusion),
+        link(str_at(run, "/html_url"), str_at(run, "/name")),
+        h(status),
+        repo(p),
+        h(str_at(run, "/head_branch"))
+    )
+}
+
+fn render_check_suite(p: &Value) -> String {
+    let suite = &p["check_suite"];
+    let conclusion = str_at(suite, "/conclusion");
+    let status = match conclusion {
+        "" => str_at(suite, "/status"),
+        c => c,
+    };
+    format!(
+        "{} {} checks {} on {}:<code>{}</code> ({})",
+        conclusion_icon(conclusion),
+        h(str_at(suite, "/app/name")),
+        h(status),
+        repo(p),
+        h(str_at(suite, "/head_branch")),
+        h(short_sha(str_at(suite, "/head_sha")))
+    )
+}
+
+/// The message for `event`, or `None` if there is nothing worth sending.
+fn render(event: &str, action: &str, p: &Value) -> Option<String> {
+    match event {
+        "push" => render_push(p),
+        "pull_request" => Some(render_pull_request(p, action)),
+        "issues" => Some(render_issue(p, action)),
+        "release" => Some(render_release(p, action)),
+        "workflow_run" => Some(render_workflow_run(p)),
+        "check_suite" => Some(render_check_suite(p)),
+        _ => None,
+    }
+}
+
+pub async fn handle(
+    req: Request<Bytes>,
+    state: &AppState,
+    dest: &Destination,
+    flavor: Flavor,
+) -> Result<Response<Full<Bytes>>, BoxError> {
+    let event = match event_name(req.headers(), flavor) {
+        Some(e) => e,
+        None => {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                serde_json::json!({"error": "missing event header"}),
+            );
+        }
+    };
+    if event == "ping" {
+        return json_response(StatusCode::OK, serde_json::json!({"status": "pong"}));
+    }
+
+    let payload: Value = match serde_json::from_slice(req.body()) {
+        Ok(v) => v,
+        Err(e) => {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                serde_json::json!({"error": format!("invalid JSON: {}", e)}),
+            );
+        }
+    };
+    let action = str_at(&payload, "/action");
+
+    let config = match flavor {
+        Flavor::Github => state.github.as_ref(),
+        Flavor::Gitea => state.gitea.as_ref(),
+    };
+    // Anything not delivered still gets a 2xx so the sender doesn't mark
+    // the hook as failing.
+    let message = match render(&event, action, &payload) {
+        Some(m) if allowed(config, &event, action) => m,
+        _ => {
+            return json_response(StatusCode::OK, serde_json::json!({"status": "ignored"}));
+        }
+    };
+
+    let overflow = overflow_header(req.headers());
+    let options = MessageOptions {
+        parse_mode: Some("HTML".to_string()),
+        ..Default::default()
+    };
+    deliver_message(state, dest, message, options, overflow).await
+}
@@ -0,0 +1,141 @@
+//! `POST /gotify/message`: Gotify-compatible publishing. The application
+//! token (`?token=`, `X-Gotify-Key` or a bearer token) is checked like an
+//! API key. The body is JSON or a form with `message`, `title` and
+//! `priority`.
+
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::{Request, Response, StatusCode};
+use serde::Deserialize;
+
+use crate::priority::Priority;
+use crate::telegram::{Destination, MessageOptions};
+use crate::template::escape;
+use crate::{deliver_message, json_response, overflow_header, AppState, BoxError};
+
+#[derive(Deserialize)]
+struct GotifyMessage {
+    message: String,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    priority: Option<i64>,
+    #[serde(default)]
+    extras: Option<serde_json::Value>,
+}
+
+/// The application token, if the request carries one the Gotify way.
+pub fn presented_token<B>(req: &Request<B>) -> Option<String> {
+    let query = req.uri().query().unwrap_or("");
+    form_urlencoded::parse(query.as_bytes())
+        .find(|(k, _)| k == "token")
+        .map(|(_, v)| v.into_owned())
+        .or_else(|| {
+            req.headers()
+                .get("x-gotify-key")
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.trim().to_string())
+        })
+}
+
+async fn parse(req: &Request<Bytes>) -> Result<GotifyMessage, String> {
+    let content_type = req
+        .headers()
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    if content_type.starts_with("application/json") {
+        return serde_json::from_slice(req.body()).map_err(|e| format!("invalid JSON: {}", e));
+    }
+
+    let mut fields: Vec<(String, String)> = Vec::new();
+    match multer::parse_boundary(content_type) {
+        Ok(boundary) => {
+            let body = Full::new(req.body().clone()).into_data_stream();
+            let mut multipart = multer::Multipart::new(body, boundary);
+            while let Some(field) = multipart
+                .next_field()
+                .await
+                .map_err(|e| format!("invalid multipart body: {}", e))?
+            {
+                let name = field.name().unwrap_or("").to_string();
+                let value = field
+                    .text()
+                    .await
+                    .map_err(|e| format!("invalid multipart body: {}", e))?;
+                fields.push((name, value));
+            }
+        }
+        Err(_) => fields.extend(form_urlencoded::parse(req.body()).into_owned()),
+    }
+
+    let field = |name: &str| {
+        fields
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.clone())
+    };
+    let priority = match field("priority") {
+        Some(p) => Some(
+            p.trim()
+                .parse()
+                .map_err(|_| format!("invalid priority: {}", p))?,
+        ),
+        None => None,
+    };
+    Ok(GotifyMessage {
+        message: field("message").ok_or("missing message")?,
+        title: field("title"),
+        priority,
+        extras: None,
+    })
+}
+
+pub async fn handle(
+    req: Request<Bytes>,
+    state: &AppState,
+    dest: &Destination,
+) -> Result<Response<Full<Bytes>>, BoxError> {
+    let overflow = overflow_header(req.headers());
+    let msg = match parse(&req).await {
+        Ok(m) if !m.message.trim().is_empty() => m,
+        Ok(_) => {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                serde_json::json!({"error": "empty message"}),
+            );
+        }
+        Err(e) => {
+            return json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": e}));
+        }
+    };
+
+    let mut text = String::new();
+    if let Some(title) = msg.title.as_deref().filter(|t| !t.is_empty()) {
+        text.push_str(&format!("<b>{}</b>\n", escape(title, Some("HTML"))));
+    }
+    text.push_str(&escape(&msg.message, Some("HTML")));
+    let click = msg
+        .extras
+        .as_ref()
+        .and_then(|e| e.pointer("/client::notification/click/url"))
+        .and_then(|v| v.as_str());
+    if let Some(url) = click {
+        text.push_str(&format!(
+            "\n<a href=\"{}\">Open</a>",
+            escape(url, Some("HTML"))
+        ));
+    }
+
+    // Gotify treats a missing priority as the application's default.
+    let priority = msg
+        .priority
+        .map_or(Priority::Default, Priority::from_gotify);
+    let options = MessageOptions {
+        parse_mode: Some("HTML".to_string()),
+        silent: priority.is_silent(),
+        urgent: priority.is_urgent(),
+        ..Default::default()
+    };
+    deliver_message(state, dest, text, options, overflow).await
+}
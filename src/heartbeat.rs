@@ -0,0 +1,216 @@
+//! Heartbeats: a dead man's switch for cron jobs and backups.
+//!
+//! Each configured heartbeat expects a `POST /heartbeat/<name>` at least
+//! every `interval_secs`. When one is `grace_secs` late, an alert is sent
+//! to its destination; the next check-in sends a recovery message. Check-in
+//! times are kept in `<data_dir>/heartbeats.json`. Check-ins sent while the
+//! relay was down are lost, so after a restart the clock starts over.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, SecondsFormat};
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::config::HeartbeatConfig;
+use crate::telegram::{send_telegram_message, MessageOptions};
+use crate::util::now_secs;
+use crate::{duration, json_response, persist, AppState, BoxError};
+
+/// How often heartbeats are checked for missed check-ins.
+const CHECK_TICK: Duration = Duration::from_secs(5);
+
+/// A heartbeat's state, as saved in `heartbeats.json`. Times are Unix
+/// seconds.
+#[derive(Clone, Default, Deserialize, Serialize)]
+struct Beat {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_ping: Option<u64>,
+    /// When the missed check-in was due, set once it is alerted; the next
+    /// check-in recovers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    down_since: Option<u64>,
+    /// A check-in ended an outage that lasted this long; its recovery
+    /// message is yet to be sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    recovered_after: Option<u64>,
+}
+
+pub struct Heartbeats {
+    path: PathBuf,
+    configs: BTreeMap<String, HeartbeatConfig>,
+    started_at: u64,
+    beats: Mutex<HashMap<String, Beat>>,
+}
+
+impl Heartbeats {
+    pub fn open(
+        path: PathBuf,
+        configs: BTreeMap<String, HeartbeatConfig>,
+    ) -> Result<Heartbeats, BoxError> {
+        let mut beats: HashMap<String, Beat> = persist::load(&path)?;
+        beats.retain(|name, _| configs.contains_key(name));
+        Ok(Heartbeats {
+            path,
+            configs,
+            started_at: now_secs(),
+            beats: Mutex::new(beats),
+        })
+    }
+
+    fn save(&self, beats: &HashMap<String, Beat>) {
+        if let Err(e) = persist::save(&self.path, beats) {
+            error!("failed to save heartbeats", error = e);
+        }
+    }
+
+    /// When the next check-in is due, alerts aside.
+    fn due_at(&self, config: &HeartbeatConfig, beat: &Beat) -> u64 {
+        let from = beat.last_ping.unwrap_or(0).max(self.started_at);
+        from + config.interval_secs
+    }
+}
+
+/// `POST /heartbeat/<name>`: record a check-in.
+pub fn handle(state: &AppState, name: &str) -> Result<Response<Full<Bytes>>, BoxError> {
+    let heartbeats = &state.heartbeats;
+    let Some(config) = heartbeats.configs.get(name) else {
+        return json_response(
+            StatusCode::NOT_FOUND,
+            serde_json::json!({"error": format!("unknown heartbeat: {}", name)}),
+        );
+    };
+
+    let now = now_secs();
+    let mut beats = heartbeats.beats.lock().unwrap();
+    let beat = beats.entry(name.to_string()).or_default();
+    beat.last_ping = Some(now);
+    if let Some(since) = beat.down_since.take() {
+        beat.recovered_after = Some(now.saturating_sub(since));
+        info!("heartbeat recovered", heartbeat = name);
+    }
+    let due_at = heartbeats.due_at(config, beat);
+    let recovering = beat.recovered_after.is_some();
+    heartbeats.save(&beats);
+    let next_due = DateTime::from_timestamp(due_at as i64, 0)
+        .map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true))
+        .unwrap_or_default();
+
+    json_response(
+        StatusCode::OK,
+        serde_json::json!({
+            "status": if recovering { "recovered" } else { "ok" },
+            "heartbeat": name,
+            "next_due": next_due,
+        }),
+    )
+}
+
+/// Alert missed check-ins and announce recoveries.
+pub async fn run_checker(state: Arc<AppState>) {
+    let heartbeats = &state.heartbeats;
+    if heartbeats.configs.is_empty() {
+        return;
+    }
+    loop {
+        tokio::time::sleep(CHECK_TICK).await;
+
+        let now = now_secs();
+        let mut alerts = Vec::new();
+        let mut recoveries = Vec::new();
+        {
+            let beats = heartbeats.beats.lock().unwrap();
+            for (name, config) in &heartbeats.configs {
+                let beat = beats.get(name).cloned().unwrap_or_default();
+                if let Some(after) = beat.recovered_after {
+                    recoveries.push((name, config, after));
+                }
+                let due_at = heartbeats.due_at(config, &beat);
+                if beat.down_since.is_none() && now >= due_at + config.grace_secs {
+                    alerts.push((name, config, beat.last_ping, due_at));
+                }
+            }
+        }
+
+        // What was announced is only recorded once the message is out, so
+        // a failed send is tried again on the next tick.
+        for (name, config, after) in recoveries {
+            let text = format!(
+                "✅ Heartbeat {} is back after {}",
+                name,
+                duration::format(Duration::from_secs(after))
+            );
+            if notify(&state, name, config, &text).await {
+                let mut beats = heartbeats.beats.lock().unwrap();
+                if let Some(beat) = beats.get_mut(name) {
+                    beat.recovered_after = None;
+                }
+                heartbeats.save(&beats);
+            }
+        }
+        for (name, config, last_ping, due_at) in alerts {
+            warn!("heartbeat missed", heartbeat = name);
+            let text = match last_ping {
+                Some(t) => format!(
+                    "⚠️ Heartbeat {} is overdue: last check-in {} ago, expected every {}",
+                    name,
+                    duration::format(Duration::from_secs(now.saturating_sub(t))),
+                    duration::format(Duration::from_secs(config.interval_secs))
+                ),
+                None => format!(
+                    "⚠️ Heartbeat {} is overdue: no check-in yet, expected every {}",
+                    name,
+                    duration::format(Duration::from_secs(config.interval_secs))
+                ),
+            };
+            if notify(&state, name, config, &text).await {
+                let mut beats = heartbeats.beats.lock().unwrap();
+                let beat = beats.entry(name.clone()).or_default();
+                match beat.last_ping {
+                    // A check-in came while the alert was being sent.
+                    Some(t) if beat.last_ping != last_ping => {
+                        beat.recovered_after = Some(t.saturating_sub(due_at));
+                    }
+                    _ => beat.down_since = Some(due_at),
+                }
+                heartbeats.save(&beats);
+            }
+        }
+    }
+}
+
+/// Send `text` to the heartbeat's destination, through the outbox if it is
+/// enabled. Returns whether it was sent or queued.
+async fn notify(state: &AppState, name: &str, config: &HeartbeatConfig, text: &str) -> bool {
+    let Some(dest) = state.destination(config.destination.as_deref()) else {
+        return false;
+    };
+    let result = match &state.outbox {
+        Some(outbox) => outbox
+            .enqueue(
+                &dest.name,
+                text.to_string(),
+                MessageOptions::default(),
+                false,
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+        None => send_telegram_message(state, &dest, text, &MessageOptions::default())
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+    };
+    if let Err(e) = &result {
+        error!(
+            "failed to send heartbeat message, retrying",
+            heartbeat = name,
+            error = e
+        );
+    }
+    result.is_ok()
+}
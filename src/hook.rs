@@ -0,0 +1,70 @@
+//! `POST /hook/<name>`: render an arbitrary JSON payload through the named
+//! template from `templates` in the config.
+
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::{Request, Response, StatusCode};
+
+use crate::split::Overflow;
+use crate::telegram::{Destination, MessageOptions};
+use crate::template::Template;
+use crate::{deliver_message, json_response, overflow_header, AppState, BoxError};
+
+pub async fn handle(
+    req: Request<Bytes>,
+    state: &AppState,
+    dest: &Destination,
+    name: &str,
+) -> Result<Response<Full<Bytes>>, BoxError> {
+    let template = match state.templates.get(name) {
+        Some(t) => t,
+        None => {
+            return json_response(
+                StatusCode::NOT_FOUND,
+                serde_json::json!({"error": format!("unknown template: {}", name)}),
+            );
+        }
+    };
+    let overflow = overflow_header(req.headers());
+    let payload: serde_json::Value = match serde_json::from_slice(req.body()) {
+        Ok(v) => v,
+        Err(e) => {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                serde_json::json!({"error": format!("invalid JSON: {}", e)}),
+            );
+        }
+    };
+
+    render_and_deliver(state, dest, template, &payload, overflow).await
+}
+
+/// Render `payload` with `template` and send the result. A template that
+/// renders to nothing sends nothing.
+pub async fn render_and_deliver(
+    state: &AppState,
+    dest: &Destination,
+    template: &Template,
+    payload: &serde_json::Value,
+    overflow: Overflow,
+) -> Result<Response<Full<Bytes>>, BoxError> {
+    let message = match template.render(payload) {
+        Ok(m) => m,
+        Err(e) => {
+            warn!("template failed", error = e);
+            return json_response(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                serde_json::json!({"error": format!("template error: {}", e)}),
+            );
+        }
+    };
+    if message.is_empty() {
+        return json_response(StatusCode::OK, serde_json::json!({"status": "skipped"}));
+    }
+
+    let options = MessageOptions {
+        parse_mode: template.parse_mode.map(String::from),
+        ..Default::default()
+    };
+    deliver_message(state, dest, message, options, overflow).await
+}
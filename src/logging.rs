@@ -0,0 +1,188 @@
+//! Structured logging to stderr.
+//!
+//! Every line carries the id of the request being handled, if any, and is
+//! scrubbed of configured secrets (bot tokens, API keys, webhook secrets)
+//! before it is written.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+use chrono::{DateTime, SecondsFormat};
+
+use crate::config::LogFormat;
+use crate::util::now_millis;
+
+const REDACTED: &str = "[REDACTED]";
+
+tokio::task_local! {
+    pub static REQUEST_ID: String;
+}
+
+#[derive(Clone, Copy)]
+pub enum Level {
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+}
+
+struct Logger {
+    format: LogFormat,
+    secrets: Vec<String>,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Set the output format and the secrets to scrub. Lines logged before this
+/// use the text format without redaction.
+pub fn init(format: LogFormat, secrets: Vec<String>) {
+    let mut secrets: Vec<String> = secrets.into_iter().filter(|s| !s.is_empty()).collect();
+    // Longest first so a secret containing another is replaced whole.
+    secrets.sort_by_key(|s| std::cmp::Reverse(s.len()));
+    let _ = LOGGER.set(Logger { format, secrets });
+}
+
+/// Replace every configured secret in `s`.
+pub fn redact(s: &str) -> String {
+    let mut out = s.to_string();
+    if let Some(logger) = LOGGER.get() {
+        for secret in &logger.secrets {
+            if out.contains(secret.as_str()) {
+                out = out.replace(secret.as_str(), REDACTED);
+            }
+        }
+    }
+    out
+}
+
+/// Use the caller's `X-Request-Id` if it looks sane, otherwise make one up.
+pub fn request_id(headers: &hyper::HeaderMap) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let incoming = headers
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| {
+            !v.is_empty()
+                && v.len() <= 64
+                && v.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+        });
+    match incoming {
+        Some(id) => id.to_string(),
+        None => {
+            let millis = now_millis();
+            let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+            format!("{:011x}{:05x}", millis, n & 0xfffff)
+        }
+    }
+}
+
+/// Format a Unix timestamp in milliseconds as RFC 3339 UTC.
+fn rfc3339(millis: u64) -> String {
+    DateTime::from_timestamp_millis(millis as i64)
+        .map(|t| t.to_rfc3339_opts(SecondsFormat::Millis, true))
+        .unwrap_or_default()
+}
+
+fn logfmt_value(v: &str) -> String {
+    if !v.is_empty() && !v.contains(|c: char| c.is_whitespace() || c == '"' || c == '=') {
+        v.to_string()
+    } else {
+        format!("{:?}", v)
+    }
+}
+
+pub fn emit(level: Level, msg: &str, fields: &[(&str, String)]) {
+    let format = LOGGER.get().map(|l| l.format).unwrap_or_default();
+    let request_id = REQUEST_ID.try_with(|id| id.clone()).ok();
+    let mut fields: Vec<(&str, &str)> = fields.iter().map(|(k, v)| (*k, v.as_str())).collect();
+    if let Some(id) = &request_id {
+        fields.insert(0, ("request_id", id));
+    }
+
+    let mut line = String::new();
+    match format {
+        LogFormat::Text => {
+            if !matches!(level, Level::Info) {
+                let _ = write!(line, "{}: ", level.as_str());
+            }
+            line.push_str(msg);
+            for (k, v) in &fields {
+                let _ = write!(line, " {}={}", k, logfmt_value(v));
+            }
+        }
+        LogFormat::Logfmt => {
+            let ts = rfc3339(now_millis());
+            let _ = write!(
+                line,
+                "ts={} level={} msg={}",
+                ts,
+                level.as_str(),
+                logfmt_value(msg)
+            );
+            for (k, v) in &fields {
+                let _ = write!(line, " {}={}", k, logfmt_value(v));
+            }
+        }
+        LogFormat::Json => {
+            // Built by hand to keep ts, level and msg first.
+            let quote = |s: &str| serde_json::Value::from(s).to_string();
+            let _ = write!(
+                line,
+                "{{\"ts\":{},\"level\":{},\"msg\":{}",
+                quote(&rfc3339(now_millis())),
+                quote(level.as_str()),
+                quote(msg)
+            );
+            for (k, v) in &fields {
+                let _ = write!(line, ",{}:{}", quote(k), quote(v));
+            }
+            line.push('}');
+        }
+    }
+
+    eprintln!("{}", redact(&line));
+}
+
+/// `info!("message", key = value, ...)`; values only need `Display`.
+macro_rules! info {
+    ($msg:expr $(, $key:ident = $val:expr)* $(,)?) => {
+        $crate::logging::emit(
+            $crate::logging::Level::Info,
+            $msg,
+            &[$((stringify!($key), $val.to_string())),*],
+        )
+    };
+}
+
+macro_rules! warn {
+    ($msg:expr $(, $key:ident = $val:expr)* $(,)?) => {
+        $crate::logging::emit(
+            $crate::logging::Level::Warn,
+            $msg,
+            &[$((stringify!($key), $val.to_string())),*],
+        )
+    };
+}
+
+macro_rules! error {
+    ($msg:expr $(, $key:ident = $val:expr)* $(,)?) => {
+        $crate::logging::emit(
+            $crate::logging::Level::Error,
+            $msg,
+            &[$((stringify!($key), $val.to_string())),*],
+        )
+    };
+}
@@ -1,42 +1,191 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Instant;
 
-use http_body_util::{BodyExt, Full};
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
 use hyper::body::Bytes;
+use hyper::header::HeaderValue;
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Method, Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use tokio::net::TcpListener;
 
-#[derive(Clone, Deserialize, Serialize)]
-struct Config {
-    listen_addr: String,
-    telegram_bot_token: String,
-    telegram_username: String,
-    #[serde(default)]
-    telegram_chat_id: Option<i64>,
-    #[serde(default)]
-    path_prefix: Option<String>,
-}
+#[macro_use]
+mod logging;
+
+mod activity;
+mod alertmanager;
+mod auth;
+mod batch;
+mod buttons;
+mod commands;
+mod config;
+mod cron;
+mod dedupe;
+mod discord;
+mod duration;
+mod github;
+mod gotify;
+mod heartbeat;
+mod hook;
+mod markup;
+mod messages;
+mod metrics;
+mod ntfy;
+mod outbox;
+mod persist;
+mod priority;
+mod quiet;
+mod ratelimit;
+mod recurring;
+mod scheduled;
+mod signature;
+mod slack;
+mod split;
+mod status;
+mod telegram;
+mod template;
+mod updates;
+mod upload;
+mod util;
+
+use auth::AuthError;
+use config::{load_config, save_config, ApiKeyConfig, ForgeConfig};
+use metrics::Metrics;
+use outbox::Outbox;
+use priority::Priority;
+use ratelimit::RateLimiter;
+use signature::Verifier;
+use split::Overflow;
+use telegram::{
+    resolve_chat_id, send_telegram_message, send_text_document, Destination, MediaKind,
+    MessageOptions, SendError,
+};
+use template::Template;
 
-#[derive(Clone)]
 struct AppState {
-    telegram_token: String,
-    chat_id: i64,
+    telegram_api_base: String,
+    /// Every configured destination by name, including [`DEFAULT_DESTINATION`].
+    destinations: HashMap<String, Arc<Destination>>,
+    api_keys: Vec<ApiKeyConfig>,
+    signatures: Verifier,
     http_client: reqwest::Client,
     path_prefix: String,
+    outbox: Option<Arc<Outbox>>,
+    rate_limiter: RateLimiter,
+    max_retry_after_secs: u64,
+    metrics: Metrics,
+    alertmanager_template: Template,
+    github: Option<ForgeConfig>,
+    gitea: Option<ForgeConfig>,
+    /// Named templates for `/hook/<name>`.
+    templates: HashMap<String, Template>,
+    /// Button answers; only tracked when `updates` is configured.
+    answers: Option<buttons::Answers>,
+    statuses: status::Statuses,
+    deduper: dedupe::Deduper,
+    batcher: batch::Batcher,
+    held: quiet::Held,
+    heartbeats: heartbeat::Heartbeats,
+    schedule: scheduled::Schedule,
+    schedules: recurring::Schedules,
+    mute: quiet::Mute,
+    activity: activity::Activity,
+    /// Who may use bot commands; see [`commands`].
+    admins: Vec<String>,
+}
+
+impl AppState {
+    /// Look up a destination by name; `None` picks the default one.
+    fn destination(&self, name: Option<&str>) -> Option<Arc<Destination>> {
+        self.destinations
+            .get(name.unwrap_or(DEFAULT_DESTINATION))
+            .cloned()
+    }
+}
+
+/// A matched route. Its name is what API key `routes` scopes refer to.
+#[derive(Clone, Copy, PartialEq)]
+enum Route {
+    Message,
+    Document,
+    Photo,
+    Alertmanager,
+    Github,
+    Gitea,
+    Hook,
+    Ntfy,
+    Gotify,
+    Slack,
+    Discord,
+    MessageResponse,
+    EditMessage,
+    DeleteMessage,
+    Status,
+    DeleteStatus,
+    Heartbeat,
+    Scheduled,
+    CancelScheduled,
+    Health,
+    Metrics,
+}
+
+impl Route {
+    fn name(self) -> &'static str {
+        match self {
+            Route::Message => "message",
+            Route::Document => "document",
+            Route::Photo => "photo",
+            Route::Alertmanager => "alertmanager",
+            Route::Github => "github",
+            Route::Gitea => "gitea",
+            Route::Hook => "hook",
+            Route::Ntfy => "ntfy",
+            Route::Gotify => "gotify",
+            Route::Slack => "slack",
+            Route::Discord => "discord",
+            Route::MessageResponse | Route::EditMessage | Route::DeleteMessage => "messages",
+            Route::Status | Route::DeleteStatus => "status",
+            Route::Heartbeat => "heartbeat",
+            Route::Scheduled | Route::CancelScheduled => "scheduled",
+            Route::Health => "health",
+            Route::Metrics => "metrics",
+        }
+    }
 }
 
 #[derive(Deserialize)]
 struct SendRequest {
     message: String,
+    #[serde(default)]
+    buttons: Option<buttons::Layout>,
+    #[serde(default)]
+    reply_to: Option<i64>,
+    #[serde(default)]
+    dedupe_key: Option<String>,
+    #[serde(default)]
+    priority: Option<String>,
+    #[serde(default)]
+    send_at: Option<String>,
+    #[serde(default)]
+    delay: Option<String>,
 }
 
 type BoxError = Box<dyn std::error::Error + Send + Sync>;
 
+const DEFAULT_TELEGRAM_API_BASE: &str = "https://api.telegram.org";
+const DEFAULT_DATA_DIR: &str = "data";
+
+/// Name of the destination built from `telegram_username`/`telegram_chat_id`.
+const DEFAULT_DESTINATION: &str = "default";
+
+/// Largest request body accepted, except for uploads.
+const MAX_BODY_BYTES: usize = 1 << 20;
+
 /// Normalize a path prefix: trim whitespace, strip trailing slashes,
 /// ensure a leading slash. Returns empty string if effectively blank.
 fn normalize_prefix(raw: Option<&str>) -> String {
@@ -51,33 +200,248 @@ fn normalize_prefix(raw: Option<&str>) -> String {
     }
 }
 
-async fn send_telegram_message(
+/// Options for part `i` of `count`: only the first part is a reply, and
+/// buttons only go under the last one.
+fn part_options(options: &MessageOptions, i: usize, count: usize) -> MessageOptions {
+    let mut options = options.clone();
+    if i > 0 {
+        options.reply_to = None;
+    }
+    if i + 1 < count {
+        options.buttons.clear();
+    }
+    options
+}
+
+/// Send the parts of one message in order, stopping at the first failure.
+/// Returns the message ids of the parts.
+async fn send_parts(
     state: &AppState,
-    text: &str,
-    parse_mode: Option<&str>,
-) -> Result<(), BoxError> {
-    let url = format!(
-        "https://api.telegram.org/bot{}/sendMessage",
-        state.telegram_token
-    );
+    dest: &Destination,
+    parts: &[String],
+    options: &MessageOptions,
+    as_document: bool,
+) -> Result<Vec<i64>, SendError> {
+    let mut message_ids = Vec::new();
+    for (i, part) in parts.iter().enumerate() {
+        let options = part_options(options, i, parts.len());
+        let message_id = if as_document {
+            let caption = split::document_caption(part);
+            send_text_document(state, dest, "message.txt", part, Some(&caption), &options).await?
+        } else {
+            send_telegram_message(state, dest, part, &options).await?
+        };
+        message_ids.push(message_id);
+    }
+    Ok(message_ids)
+}
 
-    let mut body = serde_json::json!({
-        "chat_id": state.chat_id,
-        "text": text,
-    });
-    if let Some(mode) = parse_mode {
-        body["parse_mode"] = serde_json::json!(mode);
+/// What [`deliver`] did with a message.
+enum Delivered {
+    /// Held for the end of quiet hours or a mute.
+    Held,
+    /// Added to an open digest, which now holds this many messages.
+    Batched(usize),
+    /// Queued in the outbox: the first part's id, and the number of parts.
+    Queued { id: u64, parts: usize },
+    /// Sent right away: the message id of each part.
+    Sent {
+        message_ids: Vec<i64>,
+        parts: Vec<String>,
+        as_document: bool,
+    },
+}
+
+enum DeliverError {
+    /// Holding or queueing the message failed.
+    Store(String),
+    Send(SendError),
+}
+
+impl DeliverError {
+    /// Whether delivering the same message again can never succeed.
+    fn is_permanent(&self) -> bool {
+        match self {
+            DeliverError::Store(_) => false,
+            DeliverError::Send(e) => e.is_permanent(),
+        }
     }
 
-    let resp = state.http_client.post(&url).json(&body).send().await?;
+    fn retry_after(&self) -> Option<u64> {
+        match self {
+            DeliverError::Store(_) => None,
+            DeliverError::Send(e) => e.retry_after(),
+        }
+    }
 
-    if !resp.status().is_success() {
-        let status = resp.status();
-        let body = resp.text().await.unwrap_or_default();
-        return Err(format!("Telegram API error {}: {}", status, body).into());
+    /// When to try a held or scheduled message again after its `attempts`th
+    /// failure, or `None` to give up on it. The wait doubles from 5 seconds
+    /// up to 5 minutes, and never ends before Telegram's flood wait.
+    fn retry_at(&self, attempts: u32) -> Option<chrono::DateTime<chrono::Utc>> {
+        if self.is_permanent() {
+            return None;
+        }
+        let backoff = 5u64 << attempts.saturating_sub(1).min(6);
+        let secs = backoff.min(300).max(self.retry_after().unwrap_or(0));
+        Some(chrono::Utc::now() + chrono::TimeDelta::seconds(secs as i64))
     }
+}
 
-    Ok(())
+impl std::fmt::Display for DeliverError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeliverError::Store(e) => write!(f, "{}", e),
+            DeliverError::Send(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Hold, batch, queue or send `message`, fitting it to Telegram's length
+/// limit as `overflow` says. This is what `POST /` does once a message is
+/// accepted, and what held and scheduled messages go through when due.
+async fn deliver(
+    state: &AppState,
+    dest: &Destination,
+    message: String,
+    options: MessageOptions,
+    overflow: Overflow,
+) -> Result<Delivered, DeliverError> {
+    // Callers wait for the message id of messages with callback buttons,
+    // so those go out right away, if silently. For the same reason they
+    // skip the outbox.
+    let callbacks = buttons::has_callbacks(&options.buttons);
+    if !callbacks && quiet::should_hold(state, dest, &options) {
+        state
+            .held
+            .hold(&dest.name, message, options, overflow)
+            .map_err(|e| DeliverError::Store(format!("failed to hold message: {}", e)))?;
+        return Ok(Delivered::Held);
+    }
+
+    // Buttons and replies belong to one message, so those aren't batched.
+    if options.buttons.is_empty() && options.reply_to.is_none() {
+        let batched = state
+            .batcher
+            .add(&dest.name, &message, &options)
+            .map_err(|e| DeliverError::Store(format!("failed to batch message: {}", e)))?;
+        if let Some(count) = batched {
+            return Ok(Delivered::Batched(count));
+        }
+    }
+
+    let (parts, as_document) = split::fit(message, options.parse_mode.as_deref(), overflow);
+    if let Some(outbox) = state.outbox.as_ref().filter(|_| !callbacks) {
+        let mut first_id = None;
+        let count = parts.len();
+        for (i, part) in parts.into_iter().enumerate() {
+            let options = part_options(&options, i, count);
+            let id = outbox
+                .enqueue(&dest.name, part, options, as_document)
+                .map_err(|e| DeliverError::Store(format!("failed to queue message: {}", e)))?;
+            first_id.get_or_insert(id);
+        }
+        return Ok(Delivered::Queued {
+            id: first_id.unwrap_or_default(),
+            parts: count,
+        });
+    }
+
+    let message_ids = send_parts(state, dest, &parts, &options, as_document)
+        .await
+        .map_err(DeliverError::Send)?;
+    Ok(Delivered::Sent {
+        message_ids,
+        parts,
+        as_document,
+    })
+}
+
+/// `/messages/<id><rest>`: the message id and what follows it.
+fn message_path(path: &str) -> Option<(i64, &str)> {
+    let rest = path.strip_prefix("/messages/")?;
+    let end = rest.find('/').unwrap_or(rest.len());
+    Some((rest[..end].parse().ok()?, &rest[end..]))
+}
+
+/// Map a user-facing parse mode name to Telegram's.
+fn parse_mode_name(value: &str) -> Option<&'static str> {
+    match value.trim().to_lowercase().as_str() {
+        "markdown" => Some("MarkdownV2"),
+        "html" => Some("HTML"),
+        _ => None,
+    }
+}
+
+fn parse_mode_header(headers: &hyper::HeaderMap) -> Option<String> {
+    headers
+        .get("telegram-parse-mode")
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_mode_name)
+        .map(String::from)
+}
+
+fn reply_to_header(headers: &hyper::HeaderMap) -> Option<i64> {
+    headers
+        .get("telegram-reply-to")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok())
+}
+
+fn overflow_header(headers: &hyper::HeaderMap) -> Overflow {
+    headers
+        .get("telegram-overflow")
+        .and_then(|v| v.to_str().ok())
+        .and_then(Overflow::from_header)
+        .unwrap_or_default()
+}
+
+fn json_response(
+    status: StatusCode,
+    body: serde_json::Value,
+) -> Result<Response<Full<Bytes>>, BoxError> {
+    Ok(Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body.to_string())))?)
+}
+
+/// Response for a failed Telegram call: 503 with `Retry-After` when we were
+/// rate limited for longer than we're willing to wait, 502 otherwise.
+fn send_error_response(e: &SendError) -> Result<Response<Full<Bytes>>, BoxError> {
+    warn!("telegram send failed", error = e);
+    match e.retry_after() {
+        Some(secs) => Ok(Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header(hyper::header::RETRY_AFTER, secs)
+            .body(Full::new(Bytes::from(format!(
+                "{{\"error\": \"telegram rate limit, retry after {}s\"}}",
+                secs
+            ))))?),
+        None => Ok(Response::builder()
+            .status(StatusCode::BAD_GATEWAY)
+            .body(Full::new(Bytes::from(
+                serde_json::json!({
+                    "error": format!("telegram send failed: {}", logging::redact(&e.to_string())),
+                })
+                .to_string(),
+            )))?),
+    }
+}
+
+/// Handle one request inside its own request id scope, echoing the id in
+/// the `X-Request-Id` response header.
+async fn serve(
+    req: Request<hyper::body::Incoming>,
+    state: Arc<AppState>,
+) -> Result<Response<Full<Bytes>>, BoxError> {
+    let request_id = logging::request_id(req.headers());
+    let mut resp = logging::REQUEST_ID
+        .scope(request_id.clone(), handle_request(req, state))
+        .await?;
+    if let Ok(v) = HeaderValue::from_str(&request_id) {
+        resp.headers_mut().insert("x-request-id", v);
+    }
+    Ok(resp)
 }
 
 async fn handle_request(
@@ -94,145 +458,482 @@ async fn handle_request(
         }
     };
 
-    match (req.method(), sub) {
-        (&Method::POST, "/" | "") => {
-            let is_json = req
-                .headers()
-                .get(hyper::header::CONTENT_TYPE)
+    // `/to/<name>/...` addresses a named destination; otherwise the
+    // `telegram-recipient` header does, falling back to the default.
+    let (mut recipient, sub) = match sub.strip_prefix("/to/") {
+        Some(rest) => match rest.find('/') {
+            Some(i) => (Some(rest[..i].to_string()), &rest[i..]),
+            None => (Some(rest.to_string()), ""),
+        },
+        None => (
+            req.headers()
+                .get("telegram-recipient")
                 .and_then(|v| v.to_str().ok())
-                .map(|ct| ct.starts_with("application/json"))
-                .unwrap_or(false);
+                .map(|v| v.trim().to_string()),
+            sub,
+        ),
+    };
 
-            let parse_mode = req
-                .headers()
-                .get("telegram-parse-mode")
-                .and_then(|v| v.to_str().ok())
-                .and_then(|v| match v.to_lowercase().as_str() {
-                    "markdown" => Some("MarkdownV2"),
-                    "html" => Some("HTML"),
-                    _ => None,
-                })
-                .map(String::from);
-
-            let body_bytes = req.collect().await?.to_bytes();
-
-            let message = if is_json {
-                let payload: SendRequest = match serde_json::from_slice(&body_bytes) {
-                    Ok(p) => p,
-                    Err(e) => {
-                        return Ok(Response::builder()
-                            .status(StatusCode::BAD_REQUEST)
-                            .body(Full::new(Bytes::from(format!(
-                                "{{\"error\": \"invalid JSON: {}\"}}",
-                                e
-                            ))))?);
-                    }
-                };
-                payload.message
-            } else {
-                let text = String::from_utf8(body_bytes.to_vec()).map_err(|e| {
-                    format!("invalid UTF-8 in request body: {}", e)
-                })?;
-                if text.is_empty() {
-                    return Ok(Response::builder()
-                        .status(StatusCode::BAD_REQUEST)
-                        .body(Full::new(Bytes::from(
-                            "{\"error\": \"empty body\"}",
-                        )))?);
-                }
-                text
+    // The `<name>` of `/hook/<name>` and `/heartbeat/<name>`, the `<key>`
+    // of `/status/<key>` and the `<id>` of `/scheduled/<id>`.
+    let mut name = None;
+    let mut message_id = None;
+    let route = match (req.method(), sub) {
+        (&Method::POST, "/" | "") => Route::Message,
+        (&Method::POST, "/document") => Route::Document,
+        (&Method::POST, "/photo") => Route::Photo,
+        (&Method::POST, "/alertmanager") => Route::Alertmanager,
+        (&Method::POST, "/github") => Route::Github,
+        (&Method::POST, "/gitea") => Route::Gitea,
+        (&Method::POST, p)
+            if p.strip_prefix("/hook/")
+                .is_some_and(|n| !n.is_empty() && !n.contains('/')) =>
+        {
+            name = Some(p["/hook/".len()..].to_string());
+            Route::Hook
+        }
+        // The ntfy topic names the destination.
+        (&Method::POST | &Method::PUT, p)
+            if p.strip_prefix("/ntfy/")
+                .is_some_and(|t| !t.is_empty() && !t.contains('/')) =>
+        {
+            recipient = Some(p["/ntfy/".len()..].to_string());
+            Route::Ntfy
+        }
+        (&Method::POST, "/gotify/message") => Route::Gotify,
+        (&Method::POST, "/slack") => Route::Slack,
+        (&Method::POST, "/discord") => Route::Discord,
+        (&Method::GET, p) if message_path(p).is_some_and(|(_, rest)| rest == "/response") => {
+            message_id = message_path(p).map(|(id, _)| id);
+            Route::MessageResponse
+        }
+        (&Method::PATCH, p) if message_path(p).is_some_and(|(_, rest)| rest.is_empty()) => {
+            message_id = message_path(p).map(|(id, _)| id);
+            Route::EditMessage
+        }
+        (&Method::DELETE, p) if message_path(p).is_some_and(|(_, rest)| rest.is_empty()) => {
+            message_id = message_path(p).map(|(id, _)| id);
+            Route::DeleteMessage
+        }
+        (&Method::POST | &Method::DELETE, p)
+            if p.strip_prefix("/status/")
+                .is_some_and(|k| !k.is_empty() && !k.contains('/')) =>
+        {
+            name = Some(p["/status/".len()..].to_string());
+            match req.method() {
+                &Method::DELETE => Route::DeleteStatus,
+                _ => Route::Status,
+            }
+        }
+        // Check-ins can be a plain `curl <url>`, so GET works too.
+        (&Method::GET | &Method::POST, p)
+            if p.strip_prefix("/heartbeat/")
+                .is_some_and(|n| !n.is_empty() && !n.contains('/')) =>
+        {
+            name = Some(p["/heartbeat/".len()..].to_string());
+            Route::Heartbeat
+        }
+        (&Method::GET, "/scheduled") => Route::Scheduled,
+        (&Method::GET | &Method::DELETE, p)
+            if p.strip_prefix("/scheduled/")
+                .is_some_and(|id| !id.is_empty() && !id.contains('/')) =>
+        {
+            name = Some(p["/scheduled/".len()..].to_string());
+            match req.method() {
+                &Method::DELETE => Route::CancelScheduled,
+                _ => Route::Scheduled,
+            }
+        }
+        (&Method::GET, "/health") => Route::Health,
+        (&Method::GET, "/metrics") => Route::Metrics,
+        _ => {
+            state.metrics.inc(
+                metrics::HTTP_REQUESTS,
+                &[("route", "unmatched"), ("status", "404")],
+            );
+            return json_response(
+                StatusCode::NOT_FOUND,
+                serde_json::json!({"error": "not found"}),
+            );
+        }
+    };
+
+    let method = req.method().clone();
+    let started = Instant::now();
+    let result = handle_route(req, &state, route, name, message_id, recipient).await;
+    let status = match &result {
+        Ok(resp) => resp.status().as_u16(),
+        Err(e) => {
+            error!("request failed", error = e);
+            500
+        }
+    };
+    state.metrics.inc(
+        metrics::HTTP_REQUESTS,
+        &[("route", route.name()), ("status", &status.to_string())],
+    );
+    // The path may contain the secret prefix, so only the route is logged.
+    info!(
+        "request",
+        method = method,
+        route = route.name(),
+        status = status,
+        duration_ms = started.elapsed().as_millis()
+    );
+    result
+}
+
+async fn handle_route(
+    req: Request<hyper::body::Incoming>,
+    state: &AppState,
+    route: Route,
+    name: Option<String>,
+    message_id: Option<i64>,
+    recipient: Option<String>,
+) -> Result<Response<Full<Bytes>>, BoxError> {
+    // Scopes and signatures can name a single hook as `hook/<name>`.
+    let scope = match (route, &name) {
+        (Route::Hook, Some(name)) => format!("hook/{}", name),
+        _ => route.name().to_string(),
+    };
+
+    // Webhook senders can't attach API keys, so a route with a signature
+    // secret is authenticated by the signature instead.
+    let signature_key = [scope.as_str(), route.name()]
+        .into_iter()
+        .find(|k| state.signatures.requires(k));
+
+    if route != Route::Health && signature_key.is_none() {
+        let dest_name = recipient.as_deref().unwrap_or(DEFAULT_DESTINATION);
+        let presented = match route {
+            Route::Gotify => gotify::presented_token(&req),
+            _ => None,
+        };
+        let presented = presented
+            .as_deref()
+            .or_else(|| auth::presented_key(req.headers()));
+        if let Err(e) = auth::check(&state.api_keys, presented, &scope, dest_name) {
+            return match e {
+                AuthError::Missing | AuthError::Invalid => Ok(Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .header(hyper::header::CONTENT_TYPE, "application/json")
+                    .header(hyper::header::WWW_AUTHENTICATE, "Bearer")
+                    .body(Full::new(Bytes::from(
+                        serde_json::json!({"error": e.to_string()}).to_string(),
+                    )))?),
+                AuthError::Forbidden(_) => json_response(
+                    StatusCode::FORBIDDEN,
+                    serde_json::json!({"error": e.to_string()}),
+                ),
             };
+        }
+    }
 
-            match send_telegram_message(&state, &message, parse_mode.as_deref()).await {
-                Ok(()) => Ok(Response::builder()
-                    .status(StatusCode::OK)
-                    .body(Full::new(Bytes::from("{\"status\": \"sent\"}")))?),
-                Err(e) => Ok(Response::builder()
-                    .status(StatusCode::BAD_GATEWAY)
-                    .body(Full::new(Bytes::from(format!(
-                        "{{\"error\": \"telegram send failed: {}\"}}",
-                        e
-                    ))))?),
-            }
+    let limit = match route {
+        Route::Document | Route::Photo => upload::MAX_BODY_BYTES,
+        _ => MAX_BODY_BYTES,
+    };
+    let too_large = || {
+        json_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            serde_json::json!({
+                "error": format!("request body is larger than {} MB", limit >> 20)
+            }),
+        )
+    };
+    let (parts, body) = req.into_parts();
+    // A body declared too large is refused before reading any of it.
+    if hyper::body::Body::size_hint(&body).lower() > limit as u64 {
+        return too_large();
+    }
+    let body = match Limited::new(body, limit).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) if e.is::<LengthLimitError>() => return too_large(),
+        Err(e) => return Err(e),
+    };
+    let req = Request::from_parts(parts, body);
+
+    if let Some(key) = signature_key {
+        if let Err(e) = state.signatures.verify(key, req.headers(), req.body()) {
+            return json_response(
+                StatusCode::UNAUTHORIZED,
+                serde_json::json!({"error": format!("invalid signature: {}", e)}),
+            );
         }
+    }
+
+    let dest = match state.destination(recipient.as_deref()) {
+        Some(d) => d,
+        None => {
+            return json_response(
+                StatusCode::NOT_FOUND,
+                serde_json::json!({
+                    "error": format!("unknown destination: {}", recipient.unwrap_or_default()),
+                }),
+            );
+        }
+    };
 
-        (&Method::GET, "/health") => Ok(Response::builder()
+    match route {
+        Route::Message => handle_message(req, state, &dest).await,
+        Route::Document => upload::handle_upload(req, state, &dest, MediaKind::Document).await,
+        Route::Photo => upload::handle_upload(req, state, &dest, MediaKind::Photo).await,
+        Route::Alertmanager => alertmanager::handle(req, state, &dest).await,
+        Route::Github => github::handle(req, state, &dest, github::Flavor::Github).await,
+        Route::Gitea => github::handle(req, state, &dest, github::Flavor::Gitea).await,
+        Route::Hook => hook::handle(req, state, &dest, name.as_deref().unwrap_or_default()).await,
+        Route::Ntfy => ntfy::handle(req, state, &dest).await,
+        Route::Gotify => gotify::handle(req, state, &dest).await,
+        Route::Slack => slack::handle(req, state, &dest).await,
+        Route::Discord => discord::handle(req, state, &dest).await,
+        Route::MessageResponse => {
+            buttons::handle_response(req, state, &dest, message_id.unwrap_or_default()).await
+        }
+        Route::EditMessage => {
+            messages::handle_edit(req, state, &dest, message_id.unwrap_or_default()).await
+        }
+        Route::DeleteMessage => {
+            messages::handle_delete(state, &dest, message_id.unwrap_or_default()).await
+        }
+        Route::Status => {
+            status::handle(req, state, &dest, name.as_deref().unwrap_or_default()).await
+        }
+        Route::DeleteStatus => {
+            status::handle_delete(state, &dest, name.as_deref().unwrap_or_default()).await
+        }
+        Route::Heartbeat => heartbeat::handle(state, name.as_deref().unwrap_or_default()),
+        Route::Scheduled => scheduled::handle_get(state, &dest, name.as_deref()),
+        Route::CancelScheduled => {
+            scheduled::handle_cancel(state, &dest, name.as_deref().unwrap_or_default())
+        }
+        Route::Health => Ok(Response::builder()
             .status(StatusCode::OK)
             .body(Full::new(Bytes::from("{\"status\": \"ok\"}")))?),
-
-        _ => Ok(Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(Full::new(Bytes::from("{\"error\": \"not found\"}")))?),
+        Route::Metrics => {
+            let outbox_depth = state.outbox.as_ref().map_or(0, |o| o.len());
+            let body = state
+                .metrics
+                .render(&[(metrics::OUTBOX_DEPTH, outbox_depth as f64)]);
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header(hyper::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                .body(Full::new(Bytes::from(body)))?)
+        }
     }
 }
 
-/// Poll Telegram's getUpdates endpoint until we find a message from the
-/// configured username. Returns the chat_id for that user.
-async fn resolve_chat_id(
-    client: &reqwest::Client,
-    token: &str,
-    username: &str,
-) -> Result<i64, BoxError> {
-    let url = format!("https://api.telegram.org/bot{}/getUpdates", token);
-
-    // Normalize: strip leading @ if present
-    let username = username.strip_prefix('@').unwrap_or(username);
-
-    eprintln!(
-        "waiting for @{} to send a message to the bot...",
-        username
-    );
-    eprintln!("tell them to open the bot and send /start");
-
-    let mut offset: Option<i64> = None;
+/// Read a `POST /` or `PATCH /messages/<id>` body: plain text, or
+/// [`SendRequest`] JSON. Errors are meant for a 400 response.
+fn read_message(
+    req: &Request<Bytes>,
+    state: &AppState,
+) -> Result<(String, MessageOptions), String> {
+    let is_json = req
+        .headers()
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|ct| ct.starts_with("application/json"))
+        .unwrap_or(false);
 
-    loop {
-        let mut params = serde_json::json!({"timeout": 30});
-        if let Some(off) = offset {
-            params["offset"] = serde_json::json!(off);
-        }
+    let mut options = MessageOptions {
+        parse_mode: parse_mode_header(req.headers()),
+        reply_to: reply_to_header(req.headers()),
+        dedupe_key: req
+            .headers()
+            .get("x-dedupe-key")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty()),
+        ..Default::default()
+    };
 
-        let resp = client.post(&url).json(&params).send().await?;
-        let body: serde_json::Value = resp.json().await?;
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from)
+    };
+    let mut priority = header("telegram-priority");
+    let mut send_at = header("telegram-send-at");
+    let mut delay = header("telegram-delay");
 
-        if let Some(updates) = body["result"].as_array() {
-            for update in updates {
-                // Track offset so we don't re-process old updates
-                if let Some(id) = update["update_id"].as_i64() {
-                    offset = Some(id + 1);
-                }
+    let message = if is_json {
+        let payload: SendRequest =
+            serde_json::from_slice(req.body()).map_err(|e| format!("invalid JSON: {}", e))?;
+        options.buttons = payload
+            .buttons
+            .map(buttons::Layout::into_rows)
+            .unwrap_or_default();
+        options.reply_to = payload.reply_to.or(options.reply_to);
+        options.dedupe_key = payload.dedupe_key.or(options.dedupe_key);
+        priority = payload.priority.or(priority);
+        send_at = payload.send_at.or(send_at);
+        delay = payload.delay.or(delay);
+        payload.message
+    } else {
+        let text = std::str::from_utf8(req.body())
+            .map_err(|e| format!("invalid UTF-8 in request body: {}", e))?;
+        if text.is_empty() {
+            return Err("empty body".to_string());
+        }
+        text.to_string()
+    };
 
-                let msg = &update["message"];
-                if let Some(from_username) = msg["from"]["username"].as_str() {
-                    if from_username.eq_ignore_ascii_case(username) {
-                        if let Some(chat_id) = msg["chat"]["id"].as_i64() {
-                            eprintln!("resolved @{} -> chat_id {}", username, chat_id);
-                            return Ok(chat_id);
-                        }
-                    }
-                }
-            }
+    if let Some(p) = priority {
+        let p = Priority::from_ntfy(&p).ok_or_else(|| format!("invalid priority: {}", p))?;
+        options.silent = p.is_silent();
+        options.urgent = p.is_urgent();
+    }
+    options.send_at = match (send_at, delay) {
+        (Some(_), Some(_)) => return Err("set send_at or delay, not both".to_string()),
+        (Some(t), None) => Some(
+            chrono::DateTime::parse_from_rfc3339(t.trim())
+                .map_err(|_| format!("invalid send_at, expected RFC 3339: {}", t))?
+                .to_utc(),
+        ),
+        (None, Some(d)) => {
+            let d = duration::parse(&d).ok_or_else(|| format!("invalid delay: {}", d))?;
+            let at = chrono::TimeDelta::from_std(d)
+                .ok()
+                .and_then(|d| chrono::Utc::now().checked_add_signed(d))
+                .ok_or_else(|| "delay is too long".to_string())?;
+            Some(at)
         }
+        (None, None) => None,
+    };
+    buttons::validate(&options.buttons)?;
+    if buttons::has_callbacks(&options.buttons) && state.answers.is_none() {
+        return Err("callback buttons need `updates` in the config".to_string());
     }
+    Ok((message, options))
 }
 
-fn load_config(path: &PathBuf) -> Result<Config, BoxError> {
-    let contents = std::fs::read_to_string(path)
-        .map_err(|e| format!("failed to read config file {}: {}", path.display(), e))?;
-    let config: Config = serde_json::from_str(&contents)
-        .map_err(|e| format!("failed to parse config file {}: {}", path.display(), e))?;
-    Ok(config)
+/// `POST /`: send the body (plain text, or `{"message": ...}` JSON) as a
+/// text message.
+async fn handle_message(
+    req: Request<Bytes>,
+    state: &AppState,
+    dest: &Destination,
+) -> Result<Response<Full<Bytes>>, BoxError> {
+    let overflow = overflow_header(req.headers());
+    match read_message(&req, state) {
+        Ok((message, options)) => deliver_message(state, dest, message, options, overflow).await,
+        Err(e) => json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": e})),
+    }
 }
 
-fn save_config(path: &PathBuf, config: &Config) -> Result<(), BoxError> {
-    let contents = serde_json::to_string_pretty(config)?;
-    std::fs::write(path, contents.as_bytes())
-        .map_err(|e| format!("failed to write config file {}: {}", path.display(), e))?;
-    Ok(())
+/// Schedule, deduplicate and [`deliver`] `message`, and answer the request
+/// with the outcome.
+async fn deliver_message(
+    state: &AppState,
+    dest: &Destination,
+    message: String,
+    options: MessageOptions,
+    overflow: Overflow,
+) -> Result<Response<Full<Bytes>>, BoxError> {
+    if let Some(send_at) = options.send_at.filter(|t| *t > chrono::Utc::now()) {
+        if buttons::has_callbacks(&options.buttons) {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                serde_json::json!({"error": "messages with callback buttons can't be scheduled"}),
+            );
+        }
+        return scheduled::schedule(state, dest, send_at, message, options, overflow);
+    }
+
+    let dedupe_key = state.deduper.key(&message, &options);
+    if let Some(key) = &dedupe_key {
+        if let Some(repeat) = state.deduper.check(&dest.name, key) {
+            state.metrics.inc(
+                metrics::MESSAGES_DEDUPLICATED,
+                &[("destination", &dest.name)],
+            );
+            return json_response(
+                StatusCode::OK,
+                serde_json::json!({
+                    "status": "duplicate",
+                    "repeats": repeat.repeats,
+                    "message_id": repeat.message_id,
+                    "chat_id": dest.chat_id,
+                }),
+            );
+        }
+    }
+
+    let callbacks = buttons::has_callbacks(&options.buttons);
+    let result = deliver(state, dest, message, options.clone(), overflow).await;
+    if result.is_err() {
+        if let Some(key) = &dedupe_key {
+            state.deduper.failed(&dest.name, key);
+        }
+    }
+    match result {
+        Ok(Delivered::Held) => {
+            let until = quiet::held_until(state, dest).map(|t| t.to_rfc3339());
+            json_response(
+                StatusCode::ACCEPTED,
+                serde_json::json!({"status": "held", "until": until, "chat_id": dest.chat_id}),
+            )
+        }
+        Ok(Delivered::Batched(count)) => json_response(
+            StatusCode::ACCEPTED,
+            serde_json::json!({
+                "status": "batched",
+                "batched": count,
+                "chat_id": dest.chat_id,
+            }),
+        ),
+        Ok(Delivered::Queued { id, parts }) => {
+            let mut body = serde_json::json!({"status": "queued", "id": id});
+            if parts > 1 {
+                body["parts"] = serde_json::json!(parts);
+            }
+            json_response(StatusCode::ACCEPTED, body)
+        }
+        Ok(Delivered::Sent {
+            message_ids,
+            parts,
+            as_document,
+        }) => {
+            // The last part is the one carrying the buttons.
+            let message_id = message_ids.last().copied().unwrap_or_default();
+            if let (true, Some(answers)) = (callbacks, &state.answers) {
+                answers.track(&dest.name, message_id);
+            }
+            if let (Some(key), [part], false) = (&dedupe_key, parts.as_slice(), as_document) {
+                state
+                    .deduper
+                    .sent(&dest.name, key, message_id, part, &options);
+            }
+            let mut body = serde_json::json!({
+                "status": "sent",
+                "message_id": message_id,
+                "chat_id": dest.chat_id,
+            });
+            if message_ids.len() > 1 {
+                body["parts"] = serde_json::json!(message_ids.len());
+                body["message_ids"] = serde_json::json!(message_ids);
+            }
+            json_response(StatusCode::OK, body)
+        }
+        Err(DeliverError::Store(e)) => json_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            serde_json::json!({"error": e}),
+        ),
+        Err(DeliverError::Send(e)) => send_error_response(&e),
+    }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), BoxError> {
+async fn main() {
+    // Report startup errors through the logger so they are redacted too.
+    if let Err(e) = run().await {
+        error!("fatal", error = e);
+        std::process::exit(1);
+    }
+}
+
+async fn run() -> Result<(), BoxError> {
     let config_path = PathBuf::from(
         std::env::args()
             .nth(1)
@@ -240,16 +941,33 @@ async fn main() -> Result<(), BoxError> {
     );
 
     let mut config = load_config(&config_path)?;
+    logging::init(config.log_format.unwrap_or_default(), config.secrets());
     let client = reqwest::Client::new();
 
+    let telegram_api_base = config
+        .telegram_api_base
+        .as_deref()
+        .map(|s| s.trim().trim_end_matches('/'))
+        .filter(|s| !s.is_empty())
+        .unwrap_or(DEFAULT_TELEGRAM_API_BASE)
+        .to_string();
+    if telegram_api_base != DEFAULT_TELEGRAM_API_BASE {
+        info!("using telegram api base", url = telegram_api_base);
+    }
+
     let chat_id = match config.telegram_chat_id {
         Some(id) => {
-            eprintln!("using cached chat_id {} for @{}", id, config.telegram_username);
+            info!(
+                "using cached chat_id",
+                chat_id = id,
+                username = config.telegram_username
+            );
             id
         }
         None => {
             let id = resolve_chat_id(
                 &client,
+                &telegram_api_base,
                 &config.telegram_bot_token,
                 &config.telegram_username,
             )
@@ -258,27 +976,212 @@ async fn main() -> Result<(), BoxError> {
             // Persist resolved chat_id back to config
             config.telegram_chat_id = Some(id);
             save_config(&config_path, &config)?;
-            eprintln!("saved chat_id to {}", config_path.display());
+            info!("saved chat_id", path = config_path.display());
 
             id
         }
     };
 
+    let mut destinations = HashMap::new();
+    destinations.insert(
+        DEFAULT_DESTINATION.to_string(),
+        Arc::new(Destination {
+            name: DEFAULT_DESTINATION.to_string(),
+            token: config.telegram_bot_token.clone(),
+            chat_id,
+            quiet_hours: config
+                .quiet_hours
+                .as_ref()
+                .map(quiet::QuietHours::from_config)
+                .transpose()
+                .map_err(|e| format!("quiet_hours: {}", e))?,
+        }),
+    );
+
+    let names: Vec<String> = config.destinations.keys().cloned().collect();
+    for name in names {
+        if name == DEFAULT_DESTINATION {
+            return Err(format!("destination name \"{}\" is reserved", DEFAULT_DESTINATION).into());
+        }
+        let dest = config.destinations[&name].clone();
+        let token = dest
+            .bot_token
+            .clone()
+            .unwrap_or_else(|| config.telegram_bot_token.clone());
+        let quiet_hours = dest
+            .quiet_hours
+            .as_ref()
+            .map(quiet::QuietHours::from_config)
+            .transpose()
+            .map_err(|e| format!("destination {}: quiet_hours: {}", name, e))?;
+
+        let chat_id = match (dest.chat_id, &dest.username) {
+            (Some(id), _) => id,
+            (None, Some(username)) => {
+                let id = resolve_chat_id(&client, &telegram_api_base, &token, username).await?;
+                if let Some(d) = config.destinations.get_mut(&name) {
+                    d.chat_id = Some(id);
+                }
+                save_config(&config_path, &config)?;
+                info!(
+                    "saved chat_id",
+                    destination = name,
+                    path = config_path.display()
+                );
+                id
+            }
+            (None, None) => {
+                return Err(format!("destination {}: set chat_id or username", name).into());
+            }
+        };
+
+        info!(
+            "destination configured",
+            destination = name,
+            chat_id = chat_id
+        );
+        destinations.insert(
+            name.clone(),
+            Arc::new(Destination {
+                name,
+                token,
+                chat_id,
+                quiet_hours,
+            }),
+        );
+    }
+
     let path_prefix = normalize_prefix(config.path_prefix.as_deref());
     if !path_prefix.is_empty() {
-        eprintln!("using path prefix: {}", path_prefix);
+        info!("using path prefix", prefix = path_prefix);
     }
 
+    let data_dir = PathBuf::from(config.data_dir.as_deref().unwrap_or(DEFAULT_DATA_DIR));
+
+    let outbox = match config.outbox.clone() {
+        Some(outbox_config) => {
+            let path = data_dir.join("outbox.jsonl");
+            info!("outbox enabled", path = path.display());
+            Some(Arc::new(Outbox::open(path, outbox_config)?))
+        }
+        None => None,
+    };
+
+    let rate_limit = config.rate_limit.clone().unwrap_or_default();
+    let alertmanager_template = alertmanager::template(config.alertmanager.as_ref())?;
+    let mut templates = HashMap::new();
+    for (name, t) in &config.templates {
+        let parse_mode = t.parse_mode.as_deref().and_then(parse_mode_name);
+        let template = Template::new(&t.template, parse_mode)
+            .map_err(|e| format!("template {}: {}", name, e))?;
+        templates.insert(name.clone(), template);
+    }
+
+    for (name, h) in &config.heartbeats {
+        if h.interval_secs == 0 {
+            return Err(format!("heartbeat {}: interval_secs must be positive", name).into());
+        }
+        if let Some(d) = h
+            .destination
+            .as_deref()
+            .filter(|d| !destinations.contains_key(*d))
+        {
+            return Err(format!("heartbeat {}: unknown destination {}", name, d).into());
+        }
+    }
+
+    let schedules = recurring::Schedules::open(
+        data_dir.join("schedules.json"),
+        &config.schedules,
+        &templates,
+        &destinations,
+    )?;
+
+    let batches = config
+        .destinations
+        .iter()
+        .filter_map(|(name, d)| Some((name.clone(), d.batch.clone()?)))
+        .chain(
+            config
+                .batch
+                .clone()
+                .map(|b| (DEFAULT_DESTINATION.to_string(), b)),
+        )
+        .collect();
+    let statuses = status::Statuses::open(
+        data_dir.join("status.json"),
+        &config.status.clone().unwrap_or_default(),
+    )?;
+
     let state = Arc::new(AppState {
-        telegram_token: config.telegram_bot_token,
-        chat_id,
+        telegram_api_base,
+        destinations,
+        api_keys: config.api_keys.clone(),
+        signatures: Verifier::new(config.signatures.clone()),
         http_client: client,
         path_prefix,
+        outbox,
+        rate_limiter: RateLimiter::new(&rate_limit),
+        max_retry_after_secs: rate_limit.max_retry_after_secs,
+        metrics: Metrics::default(),
+        alertmanager_template,
+        github: config.github.clone(),
+        gitea: config.gitea.clone(),
+        templates,
+        answers: config.updates.as_ref().map(|_| buttons::Answers::default()),
+        statuses,
+        deduper: dedupe::Deduper::new(config.dedupe.as_ref()),
+        batcher: batch::Batcher::open(data_dir.join("batches.json"), batches)?,
+        held: quiet::Held::open(data_dir.join("held.json"))?,
+        heartbeats: heartbeat::Heartbeats::open(
+            data_dir.join("heartbeats.json"),
+            config.heartbeats.clone(),
+        )?,
+        schedule: scheduled::Schedule::open(data_dir.join("scheduled.json"))?,
+        schedules,
+        mute: quiet::Mute::open(data_dir.join("mute.json"))?,
+        activity: activity::Activity::default(),
+        admins: config
+            .updates
+            .as_ref()
+            .map(|u| u.admins.clone())
+            .unwrap_or_default(),
     });
 
+    if let Some(outbox) = &state.outbox {
+        tokio::spawn(outbox::run_worker(outbox.clone(), state.clone()));
+    }
+
+    tokio::spawn(status::run_flusher(state.clone()));
+    tokio::spawn(dedupe::run_counter(state.clone()));
+    tokio::spawn(batch::run_worker(state.clone()));
+    tokio::spawn(quiet::run_releaser(state.clone()));
+    tokio::spawn(heartbeat::run_checker(state.clone()));
+    tokio::spawn(scheduled::run_sender(state.clone()));
+    tokio::spawn(recurring::run(state.clone()));
+
+    if let Some(updates) = &config.updates {
+        // One poller per bot, however many destinations share it.
+        let mut tokens: Vec<String> = state
+            .destinations
+            .values()
+            .map(|d| d.token.clone())
+            .collect();
+        tokens.sort();
+        tokens.dedup();
+        info!("receiving updates", bots = tokens.len());
+        for token in tokens {
+            tokio::spawn(updates::run(
+                state.clone(),
+                token,
+                updates.poll_timeout_secs,
+            ));
+        }
+    }
+
     let addr: SocketAddr = config.listen_addr.parse()?;
     let listener = TcpListener::bind(addr).await?;
-    eprintln!("listening on {}", addr);
+    info!("listening", addr = addr);
 
     loop {
         let (stream, _) = listener.accept().await?;
@@ -286,13 +1189,10 @@ async fn main() -> Result<(), BoxError> {
         let state = state.clone();
 
         tokio::task::spawn(async move {
-            let service = service_fn(move |req| {
-                let state = state.clone();
-                handle_request(req, state)
-            });
+            let service = service_fn(move |req| serve(req, state.clone()));
 
             if let Err(e) = http1::Builder::new().serve_connection(io, service).await {
-                eprintln!("connection error: {:?}", e);
+                warn!("connection error", error = e);
             }
         });
     }
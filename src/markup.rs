@@ -0,0 +1,500 @@
+//! Converting Slack mrkdwn and Discord markdown into Telegram HTML.
+//!
+//! Both are best effort: anything we don't recognise comes through as
+//! escaped plain text, so the result is always valid HTML for Telegram.
+
+use crate::util::h;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Dialect {
+    Slack,
+    Discord,
+}
+
+impl Dialect {
+    /// Inline markers and the tags they become, longest first.
+    fn markers(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Dialect::Slack => &[("*", "b"), ("_", "i"), ("~", "s")],
+            Dialect::Discord => &[
+                ("**", "b"),
+                ("__", "u"),
+                ("~~", "s"),
+                ("||", "tg-spoiler"),
+                ("*", "i"),
+                ("_", "i"),
+            ],
+        }
+    }
+}
+
+/// Slack escapes `&`, `<` and `>` in message text; undo that before we
+/// escape again.
+fn unescape_slack(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// Convert `text` in `dialect` to Telegram HTML.
+pub fn to_html(text: &str, dialect: Dialect) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    // Code blocks first: nothing inside them is markup.
+    while let Some(start) = rest.find("```") {
+        let after = &rest[start + 3..];
+        let Some(len) = after.find("```") else {
+            break;
+        };
+        blocks(&rest[..start], dialect, &mut out);
+        let mut code = &after[..len];
+        let mut lang = "";
+        if dialect == Dialect::Discord {
+            if let Some((first, body)) = code.split_once('\n') {
+                if !first.is_empty() && !first.contains(char::is_whitespace) {
+                    lang = first;
+                    code = body;
+                }
+            }
+        }
+        let code = match dialect {
+            Dialect::Slack => unescape_slack(code),
+            Dialect::Discord => code.to_string(),
+        };
+        let code = h(code.trim_matches('\n'));
+        if lang.is_empty() {
+            out.push_str(&format!("<pre>{}</pre>", code));
+        } else {
+            out.push_str(&format!(
+                "<pre><code class=\"language-{}\">{}</code></pre>",
+                h(lang),
+                code
+            ));
+        }
+        rest = &after[len + 3..];
+    }
+    blocks(rest, dialect, &mut out);
+    out
+}
+
+/// Line-level markup: quotes, and for Discord headings and list items.
+fn blocks(text: &str, dialect: Dialect, out: &mut String) {
+    let mut quote: Vec<String> = Vec::new();
+    let flush = |quote: &mut Vec<String>, out: &mut String| {
+        if !quote.is_empty() {
+            out.push_str(&format!("<blockquote>{}</blockquote>", quote.join("\n")));
+            quote.clear();
+        }
+    };
+
+    let lines: Vec<&str> = text.split('\n').collect();
+    for (n, line) in lines.iter().enumerate() {
+        let quoted = line
+            .strip_prefix("&gt;")
+            .filter(|_| dialect == Dialect::Slack)
+            .or_else(|| line.strip_prefix('>'));
+        if let Some(q) = quoted {
+            let mut converted = String::new();
+            inline(q.strip_prefix(' ').unwrap_or(q), dialect, &mut converted);
+            quote.push(converted);
+            continue;
+        }
+        flush(&mut quote, out);
+
+        if dialect == Dialect::Discord {
+            let heading = ["### ", "## ", "# "]
+                .iter()
+                .find_map(|p| line.strip_prefix(p));
+            if let Some(title) = heading {
+                out.push_str("<b>");
+                inline(title, dialect, out);
+                out.push_str("</b>");
+            } else if let Some(item) = line.strip_prefix("- ").or_else(|| line.strip_prefix("* ")) {
+                out.push_str("• ");
+                inline(item, dialect, out);
+            } else {
+                inline(line, dialect, out);
+            }
+        } else {
+            inline(line, dialect, out);
+        }
+        if n + 1 < lines.len() {
+            out.push('\n');
+        }
+    }
+    flush(&mut quote, out);
+}
+
+/// Whether `c` can sit next to a marker and still let it count, so that
+/// `snake_case_name` or `2*3*4` stay literal.
+fn is_boundary(c: Option<char>) -> bool {
+    c.is_none_or(|c| !c.is_alphanumeric())
+}
+
+/// Where `inline` found, or failed to find, the ends of spans in one piece
+/// of text. Each opener looks ahead for its end; remembering the results
+/// keeps text full of unclosed openers from being scanned over and over.
+#[derive(Default)]
+struct Scan {
+    backtick: NextMatch,
+    angle: NextMatch,
+    link_middle: NextMatch,
+    link_end: NextMatch,
+    newline: NextMatch,
+    /// The `](` of a link found not to be followed by a valid url.
+    bad_link: Option<usize>,
+    /// For each marker, a body start from which no closing marker follows.
+    /// No later body has one either.
+    unclosed: [Option<usize>; 6],
+}
+
+/// The next occurrence of a pattern, remembered from the last search.
+#[derive(Default)]
+struct NextMatch {
+    from: usize,
+    found: Option<Option<usize>>,
+}
+
+impl NextMatch {
+    /// The first occurrence of `pattern` in `s` at or after `from`.
+    fn find(&mut self, s: &str, from: usize, pattern: &str) -> Option<usize> {
+        if let Some(found) = self.found {
+            // Nothing matched between the last start and what it found.
+            if from >= self.from && found.is_none_or(|f| f >= from) {
+                return found;
+            }
+        }
+        let found = s[from..].find(pattern).map(|j| from + j);
+        self.from = from;
+        self.found = Some(found);
+        found
+    }
+}
+
+fn inline(s: &str, dialect: Dialect, out: &mut String) {
+    let mut scan = Scan::default();
+    let mut i = 0;
+    while i < s.len() {
+        let rest = &s[i..];
+        let c = rest.chars().next().unwrap();
+        let prev = s[..i].chars().next_back();
+
+        if c == '`' {
+            if let Some(end) = scan.backtick.find(s, i + 1, "`").filter(|&e| e > i + 1) {
+                let code = &s[i + 1..end];
+                let code = match dialect {
+                    Dialect::Slack => unescape_slack(code),
+                    Dialect::Discord => code.to_string(),
+                };
+                out.push_str(&format!("<code>{}</code>", h(&code)));
+                i = end + 1;
+                continue;
+            }
+        }
+
+        match dialect {
+            Dialect::Slack => {
+                if c == '<' {
+                    if let Some(end) = scan.angle.find(s, i, ">") {
+                        out.push_str(&slack_special(&s[i + 1..end]));
+                        i = end + 1;
+                        continue;
+                    }
+                }
+                // Already-escaped entities pass through untouched.
+                if let Some(e) = ["&amp;", "&lt;", "&gt;"]
+                    .into_iter()
+                    .find(|e| rest.starts_with(e))
+                {
+                    out.push_str(e);
+                    i += e.len();
+                    continue;
+                }
+            }
+            Dialect::Discord => {
+                if c == '\\' {
+                    if let Some(next) = rest[1..]
+                        .chars()
+                        .next()
+                        .filter(|c| c.is_ascii_punctuation())
+                    {
+                        out.push_str(&h(&next.to_string()));
+                        i += 1 + next.len_utf8();
+                        continue;
+                    }
+                }
+                if c == '[' {
+                    if let Some((label, url, end)) = markdown_link(s, i, &mut scan) {
+                        out.push_str(&format!("<a href=\"{}\">", h(url)));
+                        inline(label, dialect, out);
+                        out.push_str("</a>");
+                        i = end;
+                        continue;
+                    }
+                }
+            }
+        }
+
+        let mut matched = false;
+        for (m, (marker, tag)) in dialect.markers().iter().enumerate() {
+            if !rest.starts_with(marker) {
+                continue;
+            }
+            let single = marker.len() == 1;
+            if single && !is_boundary(prev) {
+                continue;
+            }
+            let body = &rest[marker.len()..];
+            if body.starts_with(char::is_whitespace) || body.is_empty() {
+                continue;
+            }
+            let start = i + marker.len();
+            if scan.unclosed[m].is_some_and(|u| start >= u) {
+                continue;
+            }
+            // The closing marker: not after whitespace and, for single
+            // characters, not followed by a letter.
+            let close = body.match_indices(marker).find(|&(j, _)| {
+                j > 0
+                    && !body[..j].ends_with(char::is_whitespace)
+                    && (!single || is_boundary(body[j + marker.len()..].chars().next()))
+            });
+            match close {
+                Some((j, _)) => {
+                    out.push_str(&format!("<{}>", tag));
+                    inline(&body[..j], dialect, out);
+                    out.push_str(&format!("</{}>", tag));
+                    i += marker.len() + j + marker.len();
+                    matched = true;
+                    break;
+                }
+                None => scan.unclosed[m] = Some(start),
+            }
+        }
+        if matched {
+            continue;
+        }
+
+        out.push_str(&h(&c.to_string()));
+        i += c.len_utf8();
+    }
+}
+
+/// `[label](url)` at `start` in `s`: label, url and where the link ends.
+fn markdown_link<'a>(
+    s: &'a str,
+    start: usize,
+    scan: &mut Scan,
+) -> Option<(&'a str, &'a str, usize)> {
+    let middle = scan.link_middle.find(s, start, "](")?;
+    if scan
+        .newline
+        .find(s, start, "\n")
+        .is_some_and(|n| n < middle)
+    {
+        return None;
+    }
+    if scan.bad_link == Some(middle) {
+        return None;
+    }
+    let url_start = middle + 2;
+    let url_end = scan.link_end.find(s, url_start, ")")?;
+    let url = s[url_start..url_end].trim();
+    if url.contains(char::is_whitespace) {
+        scan.bad_link = Some(middle);
+        return None;
+    }
+    Some((&s[start + 1..middle], url, url_end + 1))
+}
+
+/// Slack's `<...>` sequences: links, user, channel and group mentions.
+fn slack_special(inner: &str) -> String {
+    let (target, label) = match inner.split_once('|') {
+        Some((t, l)) => (t, Some(unescape_slack(l))),
+        None => (inner, None),
+    };
+    match target.chars().next() {
+        Some('@') | Some('#') => {
+            let prefix = &target[..1];
+            let name = label.unwrap_or_else(|| target[1..].to_string());
+            h(&format!("{}{}", prefix, name.trim_start_matches(prefix)))
+        }
+        Some('!') => match label {
+            Some(l) => h(&l),
+            None => {
+                let name = target[1..].split('^').next().unwrap_or("");
+                h(&format!("@{}", name))
+            }
+        },
+        _ => {
+            let url = unescape_slack(target);
+            let text = label.unwrap_or_else(|| url.clone());
+            format!("<a href=\"{}\">{}</a>", h(&url), h(&text))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slack(s: &str) -> String {
+        to_html(s, Dialect::Slack)
+    }
+
+    fn discord(s: &str) -> String {
+        to_html(s, Dialect::Discord)
+    }
+
+    #[test]
+    fn slack_formatting() {
+        assert_eq!(
+            slack("*bold* _italic_ ~strike~"),
+            "<b>bold</b> <i>italic</i> <s>strike</s>"
+        );
+        assert_eq!(slack("*bold _both_*"), "<b>bold <i>both</i></b>");
+    }
+
+    #[test]
+    fn discord_formatting() {
+        assert_eq!(
+            discord("**bold** __under__ ~~strike~~ ||spoiler||"),
+            "<b>bold</b> <u>under</u> <s>strike</s> <tg-spoiler>spoiler</tg-spoiler>"
+        );
+        assert_eq!(discord("*italic* _italic_"), "<i>italic</i> <i>italic</i>");
+        assert_eq!(
+            discord("**bold _and italic_**"),
+            "<b>bold <i>and italic</i></b>"
+        );
+    }
+
+    #[test]
+    fn literal_markers() {
+        for s in [
+            "snake_case_words",
+            "2*3*4",
+            "a * b * c",
+            "*open",
+            "close*",
+            "a * spaced*",
+            "*spaced *",
+            "**",
+            "~",
+        ] {
+            assert_eq!(slack(s), s, "{:?}", s);
+            assert_eq!(discord(s), s, "{:?}", s);
+        }
+        assert_eq!(discord("a ~~ b"), "a ~~ b");
+        assert_eq!(discord("||"), "||");
+    }
+
+    #[test]
+    fn inline_code() {
+        assert_eq!(
+            slack("run `*x* &lt;y&gt; &amp;` now"),
+            "run <code>*x* &lt;y&gt; &amp;</code> now"
+        );
+        assert_eq!(
+            discord("run `**x** <y> &` now"),
+            "run <code>**x** &lt;y&gt; &amp;</code> now"
+        );
+        assert_eq!(discord("``"), "``");
+        assert_eq!(discord("a `b"), "a `b");
+    }
+
+    #[test]
+    fn code_blocks() {
+        assert_eq!(
+            discord("before\n```rust\nlet x = *y* < 2;\n```\nafter"),
+            "before\n<pre><code class=\"language-rust\">let x = *y* &lt; 2;</code></pre>\nafter"
+        );
+        assert_eq!(discord("```\n**x**\n```"), "<pre>**x**</pre>");
+        // Slack has no languages, and escapes what's inside.
+        assert_eq!(
+            slack("```js\n_x_ &amp;&amp; y\n```"),
+            "<pre>js\n_x_ &amp;&amp; y</pre>"
+        );
+        assert_eq!(discord("```\nunclosed *x*"), "```\nunclosed <i>x</i>");
+    }
+
+    #[test]
+    fn slack_links_and_mentions() {
+        assert_eq!(
+            slack("<https://example.com/?a=1&amp;b=2|the *label*>"),
+            "<a href=\"https://example.com/?a=1&amp;b=2\">the *label*</a>"
+        );
+        assert_eq!(
+            slack("see <https://example.com>"),
+            "see <a href=\"https://example.com\">https://example.com</a>"
+        );
+        assert_eq!(slack("<@U123>"), "@U123");
+        assert_eq!(slack("<@U123|alice>"), "@alice");
+        assert_eq!(slack("<#C123|general>"), "#general");
+        assert_eq!(slack("<!here>"), "@here");
+        assert_eq!(slack("<!subteam^S123|@ops>"), "@ops");
+        assert_eq!(slack("a <b"), "a &lt;b");
+    }
+
+    #[test]
+    fn discord_links_and_escapes() {
+        assert_eq!(
+            discord("[the **label**](https://example.com/?a=1&b=2)"),
+            "<a href=\"https://example.com/?a=1&amp;b=2\">the <b>label</b></a>"
+        );
+        assert_eq!(discord("[a](b c)"), "[a](b c)");
+        assert_eq!(discord("[a\nb](c)"), "[a\nb](c)");
+        assert_eq!(discord("[a](b"), "[a](b");
+        assert_eq!(
+            discord(r"\*not italic\* \_nor this\_ \<tag\>"),
+            "*not italic* _nor this_ &lt;tag&gt;"
+        );
+        assert_eq!(discord(r"\a"), r"\a");
+    }
+
+    #[test]
+    fn plain_text_is_escaped() {
+        assert_eq!(discord("a < b && c > d"), "a &lt; b &amp;&amp; c &gt; d");
+        // Slack sends these already escaped; they aren't escaped twice.
+        assert_eq!(
+            slack("a &lt; b &amp;&amp; c &gt; d"),
+            "a &lt; b &amp;&amp; c &gt; d"
+        );
+        assert_eq!(slack("AT&T"), "AT&amp;T");
+    }
+
+    #[test]
+    fn quotes_headings_and_lists() {
+        assert_eq!(
+            slack("&gt; quoted *bold*\n&gt; more\nafter"),
+            "<blockquote>quoted <b>bold</b>\nmore</blockquote>after"
+        );
+        assert_eq!(
+            discord("# Title\n- one\n* two\n> quote"),
+            "<b>Title</b>\n• one\n• two\n<blockquote>quote</blockquote>"
+        );
+    }
+
+    #[test]
+    fn unclosed_openers() {
+        assert_eq!(slack("*a _b ~c"), "*a _b ~c");
+        assert_eq!(discord("**a __b ||c ~~d"), "**a __b ||c ~~d");
+        // A closing marker can't follow whitespace, so the span runs on.
+        assert_eq!(slack("*a *b*"), "<b>a *b</b>");
+        // A later opener still closes when an earlier one doesn't.
+        assert_eq!(slack("*a _b* c_"), "<b>a _b</b> c_");
+        assert_eq!(discord("~~a **b**"), "~~a <b>b</b>");
+    }
+
+    #[test]
+    fn many_unmatched_openers() {
+        // Each of these used to rescan the rest of the text per opener.
+        let s = "*a _b ~c ".repeat(20_000);
+        assert_eq!(slack(&s), s);
+        let s = "**a ||b ~~c [d](e f ".repeat(20_000);
+        assert_eq!(discord(&s), s);
+        let s = "<x ".repeat(20_000);
+        assert_eq!(slack(&s), "&lt;x ".repeat(20_000));
+        let s = format!("{}_end_", "*a ".repeat(20_000));
+        assert!(slack(&s).ends_with("*a <i>end</i>"));
+    }
+}
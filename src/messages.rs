@@ -0,0 +1,79 @@
+//! `PATCH /messages/<id>` and `DELETE /messages/<id>`: editing and deleting
+//! messages sent earlier, by the `message_id` a send returned.
+
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::{Request, Response, StatusCode};
+
+use crate::telegram::{delete_message, edit_message_text, Destination, SendError};
+use crate::{buttons, json_response, read_message, send_error_response, split, AppState, BoxError};
+
+/// Telegram answers 400 for messages that don't exist (any more); that's
+/// a 404 to our callers, not a failed send.
+fn error_response(e: &SendError, message_id: i64) -> Result<Response<Full<Bytes>>, BoxError> {
+    if e.is_message_gone() {
+        return json_response(
+            StatusCode::NOT_FOUND,
+            serde_json::json!({"error": format!("message {} not found", message_id)}),
+        );
+    }
+    send_error_response(e)
+}
+
+/// Replace a message's text with the body, read like a `POST /` body.
+pub async fn handle_edit(
+    req: Request<Bytes>,
+    state: &AppState,
+    dest: &Destination,
+    message_id: i64,
+) -> Result<Response<Full<Bytes>>, BoxError> {
+    let (message, options) = match read_message(&req, state) {
+        Ok(m) => m,
+        Err(e) => return json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": e})),
+    };
+    if options.send_at.is_some() {
+        return json_response(
+            StatusCode::BAD_REQUEST,
+            serde_json::json!({"error": "send_at and delay only work for new messages"}),
+        );
+    }
+    // An edit can't be split into parts, so long text is cut to fit.
+    let text = split::truncate_message(
+        &message,
+        options.parse_mode.as_deref(),
+        split::MAX_MESSAGE_LEN,
+    );
+
+    match edit_message_text(state, dest, message_id, &text, &options).await {
+        Ok(()) => {
+            if let (true, Some(answers)) =
+                (buttons::has_callbacks(&options.buttons), &state.answers)
+            {
+                answers.track(&dest.name, message_id);
+            }
+            json_response(
+                StatusCode::OK,
+                serde_json::json!({
+                    "status": "edited",
+                    "message_id": message_id,
+                    "chat_id": dest.chat_id,
+                }),
+            )
+        }
+        Err(e) => error_response(&e, message_id),
+    }
+}
+
+pub async fn handle_delete(
+    state: &AppState,
+    dest: &Destination,
+    message_id: i64,
+) -> Result<Response<Full<Bytes>>, BoxError> {
+    match delete_message(state, dest, message_id).await {
+        Ok(()) => json_response(
+            StatusCode::OK,
+            serde_json::json!({"status": "deleted", "message_id": message_id}),
+        ),
+        Err(e) => error_response(&e, message_id),
+    }
+}
@@ -0,0 +1,169 @@
+//! Prometheus metrics, rendered in the text exposition format on
+//! `GET /metrics`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+pub const HTTP_REQUESTS: &str = "relay_http_requests_total";
+pub const MESSAGES_SENT: &str = "relay_messages_sent_total";
+pub const MESSAGES_FAILED: &str = "relay_messages_failed_total";
+pub const TELEGRAM_LATENCY: &str = "relay_telegram_request_duration_seconds";
+pub const TELEGRAM_ERRORS: &str = "relay_telegram_errors_total";
+pub const RATE_LIMIT_WAIT: &str = "relay_rate_limit_wait_seconds";
+pub const OUTBOX_DEPTH: &str = "relay_outbox_depth";
+pub const MESSAGES_DEDUPLICATED: &str = "relay_messages_deduplicated_total";
+
+const HELP: &[(&str, &str)] = &[
+    (HTTP_REQUESTS, "HTTP requests handled, by route and status."),
+    (
+        MESSAGES_SENT,
+        "Messages delivered to Telegram, by destination.",
+    ),
+    (
+        MESSAGES_FAILED,
+        "Failed message delivery attempts, by destination.",
+    ),
+    (
+        TELEGRAM_LATENCY,
+        "Telegram Bot API call latency, by method.",
+    ),
+    (
+        TELEGRAM_ERRORS,
+        "Telegram Bot API errors, by method and HTTP status.",
+    ),
+    (
+        RATE_LIMIT_WAIT,
+        "Time spent waiting for the client-side rate limiter.",
+    ),
+    (OUTBOX_DEPTH, "Messages waiting in the outbox."),
+    (
+        MESSAGES_DEDUPLICATED,
+        "Messages dropped as repeats, by destination.",
+    ),
+];
+
+const BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+type Labels = Vec<(&'static str, String)>;
+
+struct Histogram {
+    /// Cumulative count per entry of [`BUCKETS`].
+    buckets: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    counters: Mutex<BTreeMap<&'static str, BTreeMap<Labels, u64>>>,
+    histograms: Mutex<BTreeMap<&'static str, BTreeMap<Labels, Histogram>>>,
+}
+
+fn to_labels(labels: &[(&'static str, &str)]) -> Labels {
+    labels.iter().map(|(k, v)| (*k, v.to_string())).collect()
+}
+
+fn write_labels(out: &mut String, labels: &Labels, extra: Option<(&str, String)>) {
+    let mut all: Vec<(&str, &str)> = labels.iter().map(|(k, v)| (*k, v.as_str())).collect();
+    if let Some((k, v)) = &extra {
+        all.push((k, v.as_str()));
+    }
+    if all.is_empty() {
+        return;
+    }
+    out.push('{');
+    for (i, (k, v)) in all.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let escaped = v
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        let _ = write!(out, "{}=\"{}\"", k, escaped);
+    }
+    out.push('}');
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str) {
+    if let Some((_, help)) = HELP.iter().find(|(n, _)| *n == name) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+    }
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+impl Metrics {
+    pub fn inc(&self, name: &'static str, labels: &[(&'static str, &str)]) {
+        let mut counters = self.counters.lock().unwrap();
+        *counters
+            .entry(name)
+            .or_default()
+            .entry(to_labels(labels))
+            .or_default() += 1;
+    }
+
+    pub fn observe(&self, name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+        let mut histograms = self.histograms.lock().unwrap();
+        let h = histograms
+            .entry(name)
+            .or_default()
+            .entry(to_labels(labels))
+            .or_insert_with(|| Histogram {
+                buckets: vec![0; BUCKETS.len()],
+                count: 0,
+                sum: 0.0,
+            });
+        for (i, bound) in BUCKETS.iter().enumerate() {
+            if value <= *bound {
+                h.buckets[i] += 1;
+            }
+        }
+        h.count += 1;
+        h.sum += value;
+    }
+
+    /// Render everything recorded so far plus the given point-in-time
+    /// gauges.
+    pub fn render(&self, gauges: &[(&'static str, f64)]) -> String {
+        let mut out = String::new();
+
+        for (name, series) in self.counters.lock().unwrap().iter() {
+            write_header(&mut out, name, "counter");
+            for (labels, value) in series {
+                out.push_str(name);
+                write_labels(&mut out, labels, None);
+                let _ = writeln!(out, " {}", value);
+            }
+        }
+
+        for (name, series) in self.histograms.lock().unwrap().iter() {
+            write_header(&mut out, name, "histogram");
+            for (labels, h) in series {
+                for (bound, count) in BUCKETS.iter().zip(&h.buckets) {
+                    let _ = write!(out, "{}_bucket", name);
+                    write_labels(&mut out, labels, Some(("le", bound.to_string())));
+                    let _ = writeln!(out, " {}", count);
+                }
+                let _ = write!(out, "{}_bucket", name);
+                write_labels(&mut out, labels, Some(("le", "+Inf".to_string())));
+                let _ = writeln!(out, " {}", h.count);
+                let _ = write!(out, "{}_sum", name);
+                write_labels(&mut out, labels, None);
+                let _ = writeln!(out, " {}", h.sum);
+                let _ = write!(out, "{}_count", name);
+                write_labels(&mut out, labels, None);
+                let _ = writeln!(out, " {}", h.count);
+            }
+        }
+
+        for (name, value) in gauges {
+            write_header(&mut out, name, "gauge");
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        out
+    }
+}
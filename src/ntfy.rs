@@ -0,0 +1,186 @@
+//! `PUT`/`POST /ntfy/<topic>`: ntfy-compatible publishing. The topic names
+//! the destination, so ntfy clients pointed at `<relay>/ntfy` work
+//! unchanged.
+//!
+//! The body is the message. `Title`, `Priority`, `Tags`, `Click`, `Attach`
+//! and `Filename` are read from headers (with or without an `X-` prefix)
+//! or from query parameters, including ntfy's short forms (`t`, `p`, ...).
+//! With `Filename` set, or a body that isn't text, the body is sent as a
+//! file instead.
+
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::{HeaderMap, Request, Response, StatusCode};
+
+use crate::priority::Priority;
+use crate::split::{truncate_message, MAX_CAPTION_LEN};
+use crate::telegram::{send_media, Destination, MediaKind, MessageOptions, Upload};
+use crate::template::escape;
+use crate::{
+    deliver_message, json_response, overflow_header, send_error_response, AppState, BoxError,
+};
+
+/// ntfy tags that are emoji short codes show up as the emoji in front of
+/// the title; this covers the ones its docs suggest.
+const EMOJI: &[(&str, &str)] = &[
+    ("+1", "👍"),
+    ("-1", "👎"),
+    ("bell", "🔔"),
+    ("computer", "💻"),
+    ("fire", "🔥"),
+    ("green_circle", "🟢"),
+    ("heavy_check_mark", "✔️"),
+    ("information_source", "ℹ️"),
+    ("loudspeaker", "📢"),
+    ("no_entry", "⛔"),
+    ("partying_face", "🥳"),
+    ("red_circle", "🔴"),
+    ("rotating_light", "🚨"),
+    ("skull", "💀"),
+    ("tada", "🎉"),
+    ("warning", "⚠️"),
+    ("white_check_mark", "✅"),
+    ("x", "❌"),
+    ("yellow_circle", "🟡"),
+];
+
+/// Parameters of one publish request.
+struct Params {
+    headers: HeaderMap,
+    query: Vec<(String, String)>,
+}
+
+impl Params {
+    /// First of `names` found as `X-<name>` or `<name>` header, or as a
+    /// query parameter.
+    fn get(&self, names: &[&str]) -> Option<String> {
+        for name in names {
+            let header = [format!("x-{}", name), name.to_string()]
+                .into_iter()
+                .find_map(|h| self.headers.get(h.as_str()).cloned());
+            if let Some(v) = header.as_ref().and_then(|v| v.to_str().ok()) {
+                return Some(v.trim().to_string());
+            }
+            if let Some((_, v)) = self
+                .query
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            {
+                return Some(v.trim().to_string());
+            }
+        }
+        None
+    }
+}
+
+/// Render the message as Telegram HTML.
+fn render(message: &str, params: &Params) -> String {
+    let mut emoji = Vec::new();
+    let mut tags = Vec::new();
+    if let Some(list) = params.get(&["tags", "tag", "ta"]) {
+        for tag in list.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            match EMOJI.iter().find(|(code, _)| *code == tag) {
+                Some((_, e)) => emoji.push(*e),
+                None => tags.push(escape(tag, Some("HTML"))),
+            }
+        }
+    }
+    let emoji = emoji.concat();
+
+    let mut out = String::new();
+    match params.get(&["title", "ti", "t"]).filter(|t| !t.is_empty()) {
+        Some(title) => {
+            if !emoji.is_empty() {
+                out.push_str(&emoji);
+                out.push(' ');
+            }
+            out.push_str(&format!("<b>{}</b>\n", escape(&title, Some("HTML"))));
+        }
+        None if !emoji.is_empty() => {
+            out.push_str(&emoji);
+            out.push(' ');
+        }
+        None => {}
+    }
+    out.push_str(&escape(message, Some("HTML")));
+
+    if !tags.is_empty() {
+        out.push_str(&format!("\n\n<i>Tags: {}</i>", tags.join(", ")));
+    }
+    for (names, label) in [
+        (&["click"][..], "Open"),
+        (&["attach", "a"][..], "Attachment"),
+    ] {
+        if let Some(url) = params.get(names).filter(|u| !u.is_empty()) {
+            out.push_str(&format!(
+                "\n<a href=\"{}\">{}</a>",
+                escape(&url, Some("HTML")),
+                label
+            ));
+        }
+    }
+    out
+}
+
+pub async fn handle(
+    req: Request<Bytes>,
+    state: &AppState,
+    dest: &Destination,
+) -> Result<Response<Full<Bytes>>, BoxError> {
+    let overflow = overflow_header(req.headers());
+    let (parts, body) = req.into_parts();
+    let params = Params {
+        query: form_urlencoded::parse(parts.uri.query().unwrap_or("").as_bytes())
+            .into_owned()
+            .collect(),
+        headers: parts.headers,
+    };
+
+    let priority = match params.get(&["priority", "prio", "p"]) {
+        Some(p) => match Priority::from_ntfy(&p) {
+            Some(p) => p,
+            None => {
+                return json_response(
+                    StatusCode::BAD_REQUEST,
+                    serde_json::json!({"error": format!("invalid priority: {}", p)}),
+                );
+            }
+        },
+        None => Priority::Default,
+    };
+    let options = MessageOptions {
+        parse_mode: Some("HTML".to_string()),
+        silent: priority.is_silent(),
+        urgent: priority.is_urgent(),
+        ..Default::default()
+    };
+
+    let file_name = params.get(&["filename", "file", "f"]);
+    let text = std::str::from_utf8(&body).ok();
+    if file_name.is_some() || text.is_none() {
+        let message = params.get(&["message", "msg", "m"]).unwrap_or_default();
+        let caption = truncate_message(&render(&message, &params), Some("HTML"), MAX_CAPTION_LEN);
+        let upload = Upload {
+            file_name: file_name.unwrap_or_else(|| "attachment".to_string()),
+            data: body,
+        };
+        let kind = MediaKind::Document;
+        return match send_media(state, dest, kind, &upload, Some(&caption), &options).await {
+            Ok(message_id) => json_response(
+                StatusCode::OK,
+                serde_json::json!({"status": "sent", "message_id": message_id, "chat_id": dest.chat_id}),
+            ),
+            Err(e) => send_error_response(&e),
+        };
+    }
+
+    // Like ntfy: the body wins, then a message parameter, then a default.
+    let message = match text.map(str::trim).filter(|t| !t.is_empty()) {
+        Some(t) => t.to_string(),
+        None => params
+            .get(&["message", "msg", "m"])
+            .filter(|m| !m.is_empty())
+            .unwrap_or_else(|| "triggered".to_string()),
+    };
+    deliver_message(state, dest, render(&message, &params), options, overflow).await
+}
@@ -0,0 +1,375 @@
+//! Durable outbox: accepted messages are appended to a JSON-lines journal
+//! and delivered by a background worker with exponential backoff, so they
+//! survive Telegram outages and relay restarts.
+
+use std::collections::{HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::config::OutboxConfig;
+use crate::split::document_caption;
+use crate::telegram::{send_telegram_message, send_text_document, MessageOptions};
+use crate::util::now_millis;
+use crate::{AppState, BoxError, DEFAULT_DESTINATION};
+
+/// Compact the journal once the queue drains and it holds at least this
+/// many records.
+const COMPACT_THRESHOLD: usize = 1024;
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Entry {
+    pub id: u64,
+    /// Name of the configured destination to deliver to.
+    #[serde(default = "default_destination")]
+    pub destination: String,
+    pub text: String,
+    #[serde(flatten)]
+    pub options: MessageOptions,
+    /// Deliver as a `.txt` attachment instead of inline text.
+    #[serde(default)]
+    pub document: bool,
+    #[serde(default)]
+    pub attempts: u32,
+    /// Unix time in milliseconds before which the entry is not retried.
+    #[serde(default)]
+    pub next_attempt_at: u64,
+}
+
+fn default_destination() -> String {
+    DEFAULT_DESTINATION.to_string()
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Record {
+    /// Written first by compaction so ids keep increasing across restarts.
+    NextId {
+        id: u64,
+    },
+    Enqueue {
+        entry: Entry,
+    },
+    Retry {
+        id: u64,
+        attempts: u32,
+        next_attempt_at: u64,
+    },
+    Done {
+        id: u64,
+    },
+}
+
+struct Inner {
+    journal: File,
+    records: usize,
+    next_id: u64,
+    pending: VecDeque<Entry>,
+}
+
+pub struct Outbox {
+    path: PathBuf,
+    config: OutboxConfig,
+    inner: Mutex<Inner>,
+    notify: Notify,
+}
+
+fn append(journal: &mut File, record: &Record) -> Result<(), BoxError> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    journal.write_all(&line)?;
+    journal.sync_data()?;
+    Ok(())
+}
+
+/// Rewrite the journal so it only holds the still-pending entries.
+fn compact(
+    path: &Path,
+    next_id: u64,
+    pending: &VecDeque<Entry>,
+) -> Result<(File, usize), BoxError> {
+    let tmp = path.with_extension("jsonl.tmp");
+    {
+        let mut file = File::create(&tmp)?;
+        append(&mut file, &Record::NextId { id: next_id })?;
+        for entry in pending {
+            append(
+                &mut file,
+                &Record::Enqueue {
+                    entry: entry.clone(),
+                },
+            )?;
+        }
+    }
+    std::fs::rename(&tmp, path)?;
+    let journal = OpenOptions::new().append(true).open(path)?;
+    Ok((journal, pending.len() + 1))
+}
+
+impl Outbox {
+    /// Open (or create) the journal at `path`, replaying any entries that
+    /// were still pending when the relay last stopped.
+    pub fn open(path: PathBuf, config: OutboxConfig) -> Result<Outbox, BoxError> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| format!("failed to create {}: {}", dir.display(), e))?;
+        }
+
+        let mut next_id = 1;
+        let mut pending: VecDeque<Entry> = VecDeque::new();
+
+        if path.exists() {
+            let file = File::open(&path)
+                .map_err(|e| format!("failed to open outbox {}: {}", path.display(), e))?;
+            for (n, line) in BufReader::new(file).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                // A torn final line from a crash mid-write is skipped rather
+                // than refusing to start.
+                let record: Record = match serde_json::from_str(&line) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        warn!("outbox: skipping bad record", line = n + 1, error = e);
+                        continue;
+                    }
+                };
+                match record {
+                    Record::NextId { id } => next_id = next_id.max(id),
+                    Record::Enqueue { entry } => {
+                        next_id = next_id.max(entry.id + 1);
+                        pending.push_back(entry);
+                    }
+                    Record::Retry {
+                        id,
+                        attempts,
+                        next_attempt_at,
+                    } => {
+                        if let Some(e) = pending.iter_mut().find(|e| e.id == id) {
+                            e.attempts = attempts;
+                            e.next_attempt_at = next_attempt_at;
+                        }
+                    }
+                    Record::Done { id } => pending.retain(|e| e.id != id),
+                }
+            }
+        }
+
+        let (journal, records) = compact(&path, next_id, &pending)
+            .map_err(|e| format!("failed to compact outbox {}: {}", path.display(), e))?;
+
+        if !pending.is_empty() {
+            info!("outbox: pending messages restored", count = pending.len());
+        }
+
+        Ok(Outbox {
+            path,
+            config,
+            inner: Mutex::new(Inner {
+                journal,
+                records,
+                next_id,
+                pending,
+            }),
+            notify: Notify::new(),
+        })
+    }
+
+    /// Durably queue a message and return its outbox id.
+    pub fn enqueue(
+        &self,
+        destination: &str,
+        text: String,
+        options: MessageOptions,
+        document: bool,
+    ) -> Result<u64, BoxError> {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = Entry {
+            id: inner.next_id,
+            destination: destination.to_string(),
+            text,
+            options,
+            document,
+            attempts: 0,
+            next_attempt_at: 0,
+        };
+        append(
+            &mut inner.journal,
+            &Record::Enqueue {
+                entry: entry.clone(),
+            },
+        )?;
+        inner.records += 1;
+        inner.next_id += 1;
+        let id = entry.id;
+        inner.pending.push_back(entry);
+        drop(inner);
+
+        self.notify.notify_one();
+        Ok(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().pending.len()
+    }
+
+    /// The entry to try next: of the oldest entry of each destination, the
+    /// one due soonest. Entries behind a failing one wait for it, but other
+    /// destinations carry on.
+    fn next(&self) -> Option<Entry> {
+        let inner = self.inner.lock().unwrap();
+        let mut seen = HashSet::new();
+        inner
+            .pending
+            .iter()
+            .filter(|e| seen.insert(e.destination.as_str()))
+            .min_by_key(|e| e.next_attempt_at)
+            .cloned()
+    }
+
+    fn mark_done(&self, id: u64) -> Result<(), BoxError> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.pending.retain(|e| e.id != id);
+        append(&mut inner.journal, &Record::Done { id })?;
+        inner.records += 1;
+
+        if inner.pending.is_empty() && inner.records >= COMPACT_THRESHOLD {
+            let (journal, records) = compact(&self.path, inner.next_id, &inner.pending)?;
+            inner.journal = journal;
+            inner.records = records;
+        }
+        Ok(())
+    }
+
+    fn mark_retry(&self, id: u64, attempts: u32, next_attempt_at: u64) -> Result<(), BoxError> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(e) = inner.pending.iter_mut().find(|e| e.id == id) {
+            e.attempts = attempts;
+            e.next_attempt_at = next_attempt_at;
+        }
+        append(
+            &mut inner.journal,
+            &Record::Retry {
+                id,
+                attempts,
+                next_attempt_at,
+            },
+        )?;
+        inner.records += 1;
+        Ok(())
+    }
+
+    fn backoff(&self, attempts: u32) -> Duration {
+        let exp = attempts.saturating_sub(1).min(32);
+        let secs = self
+            .config
+            .initial_backoff_secs
+            .saturating_mul(1u64 << exp)
+            .min(self.config.max_backoff_secs);
+        Duration::from_secs(secs.max(1))
+    }
+}
+
+/// Deliver queued messages in order per destination. A destination's
+/// oldest message is retried with exponential backoff; its messages behind
+/// it wait so that notifications never arrive out of order, while other
+/// destinations, which may be chats the bot can still reach or other bots,
+/// carry on.
+pub async fn run_worker(outbox: Arc<Outbox>, state: Arc<AppState>) {
+    loop {
+        let entry = match outbox.next() {
+            Some(e) => e,
+            None => {
+                outbox.notify.notified().await;
+                continue;
+            }
+        };
+
+        let now = now_millis();
+        if entry.next_attempt_at > now {
+            // A new message may be for another destination, ready now.
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_millis(entry.next_attempt_at - now)) => {}
+                _ = outbox.notify.notified() => {}
+            }
+            continue;
+        }
+
+        let dest = match state.destination(Some(&entry.destination)) {
+            Some(d) => d,
+            None => {
+                warn!(
+                    "outbox: dropping message for unknown destination",
+                    id = entry.id,
+                    destination = entry.destination
+                );
+                if let Err(e) = outbox.mark_done(entry.id) {
+                    error!("outbox: failed to write journal", error = e);
+                }
+                continue;
+            }
+        };
+
+        let result = if entry.document {
+            let caption = document_caption(&entry.text);
+            send_text_document(
+                &state,
+                &dest,
+                "message.txt",
+                &entry.text,
+                Some(&caption),
+                &entry.options,
+            )
+            .await
+        } else {
+            send_telegram_message(&state, &dest, &entry.text, &entry.options).await
+        };
+
+        let journal_result = match result {
+            Ok(_) => outbox.mark_done(entry.id),
+            Err(e) => {
+                let attempts = entry.attempts + 1;
+                let exhausted = outbox
+                    .config
+                    .max_attempts
+                    .is_some_and(|max| attempts >= max);
+                if e.is_permanent() || exhausted {
+                    error!(
+                        "outbox: dropping message",
+                        id = entry.id,
+                        destination = entry.destination,
+                        attempts = attempts,
+                        error = e
+                    );
+                    outbox.mark_done(entry.id)
+                } else {
+                    // Never retry before Telegram's flood wait has passed.
+                    let flood_wait = Duration::from_secs(e.retry_after().unwrap_or(0));
+                    let delay = outbox.backoff(attempts).max(flood_wait);
+                    warn!(
+                        "outbox: send failed, retrying",
+                        id = entry.id,
+                        destination = entry.destination,
+                        attempts = attempts,
+                        retry_in_secs = delay.as_secs(),
+                        error = e
+                    );
+                    outbox.mark_retry(entry.id, attempts, now_millis() + delay.as_millis() as u64)
+                }
+            }
+        };
+
+        if let Err(e) = journal_result {
+            // The in-memory queue is already updated; only durability of this
+            // step is lost. Back off so a full disk doesn't spin the worker.
+            error!("outbox: failed to write journal", error = e);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+}
@@ -0,0 +1,36 @@
+//! Small JSON state files under `data_dir`, rewritten whole on every
+//! change.
+
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::BoxError;
+
+/// Read `path`, or the default value if it doesn't exist yet.
+pub fn load<T: DeserializeOwned + Default>(path: &Path) -> Result<T, BoxError> {
+    if !path.exists() {
+        return Ok(T::default());
+    }
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let value = serde_json::from_str(&contents)
+        .map_err(|e| format!("failed to parse {}: {}", path.display(), e))?;
+    Ok(value)
+}
+
+/// Write `value` to `path` through a temporary file, so a crash leaves
+/// either the old or the new contents.
+pub fn save<T: Serialize>(path: &Path, value: &T) -> Result<(), BoxError> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("failed to create {}: {}", dir.display(), e))?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(value)?)
+        .map_err(|e| format!("failed to write {}: {}", tmp.display(), e))?;
+    std::fs::rename(&tmp, path)
+        .map_err(|e| format!("failed to write {}: {}", path.display(), e))?;
+    Ok(())
+}
@@ -0,0 +1,46 @@
+//! Message priority, as understood by the ntfy and Gotify compatible
+//! routes. Telegram only knows loud and silent, so low priorities are
+//! delivered without a notification sound.
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Min,
+    Low,
+    #[default]
+    Default,
+    High,
+    Max,
+}
+
+impl Priority {
+    /// ntfy's `1`-`5` or `min`, `low`, `default`, `high`, `max`/`urgent`.
+    pub fn from_ntfy(value: &str) -> Option<Priority> {
+        match value.trim().to_lowercase().as_str() {
+            "1" | "min" => Some(Priority::Min),
+            "2" | "low" => Some(Priority::Low),
+            "3" | "default" => Some(Priority::Default),
+            "4" | "high" => Some(Priority::High),
+            "5" | "max" | "urgent" => Some(Priority::Max),
+            _ => None,
+        }
+    }
+
+    /// Gotify's 0-10 scale, bucketed the way the Gotify Android app does.
+    pub fn from_gotify(value: i64) -> Priority {
+        match value {
+            i64::MIN..=0 => Priority::Min,
+            1..=3 => Priority::Low,
+            4..=7 => Priority::Default,
+            _ => Priority::High,
+        }
+    }
+
+    pub fn is_silent(self) -> bool {
+        self <= Priority::Low
+    }
+
+    /// High priorities ring even during quiet hours.
+    pub fn is_urgent(self) -> bool {
+        self >= Priority::High
+    }
+}
@@ -0,0 +1,502 @@
+//! Quiet hours: a daily window, in the destination's time zone, in which
+//! messages don't ring. In `silent` mode they are sent without a
+//! notification sound; in `hold` mode `POST /` messages are kept in
+//! `<data_dir>/held.json` and sent once the window ends. Messages marked
+//! urgent (`priority: high`) ring either way.
+//!
+//! The bot's `/mute` command works like `hold` quiet hours for every
+//! destination at once, until it runs out or `/unmute`.
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveTime, SecondsFormat, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{QuietHoursConfig, QuietMode};
+use crate::split::Overflow;
+use crate::telegram::{Destination, MessageOptions};
+use crate::{deliver, persist, AppState, BoxError};
+
+/// How often held messages are checked for the end of their window.
+const RELEASE_TICK: Duration = Duration::from_secs(15);
+
+pub struct QuietHours {
+    start: NaiveTime,
+    end: NaiveTime,
+    tz: Tz,
+    pub hold: bool,
+}
+
+impl QuietHours {
+    pub fn from_config(config: &QuietHoursConfig) -> Result<QuietHours, String> {
+        let time = |s: &str| {
+            NaiveTime::parse_from_str(s.trim(), "%H:%M")
+                .map_err(|_| format!("invalid time {:?}, expected HH:MM", s))
+        };
+        let start = time(&config.start)?;
+        let end = time(&config.end)?;
+        if start == end {
+            return Err("start and end are the same".to_string());
+        }
+        let tz = match config.timezone.as_deref() {
+            Some(name) => name
+                .parse()
+                .map_err(|_| format!("unknown time zone {:?}", name))?,
+            None => Tz::UTC,
+        };
+        Ok(QuietHours {
+            start,
+            end,
+            tz,
+            hold: config.mode == QuietMode::Hold,
+        })
+    }
+
+    pub fn is_quiet_at(&self, now: DateTime<Utc>) -> bool {
+        let t = now.with_timezone(&self.tz).time();
+        if self.start < self.end {
+            self.start <= t && t < self.end
+        } else {
+            t >= self.start || t < self.end
+        }
+    }
+
+    /// Whether a message with `options` goes out silently at `now`.
+    /// Urgent messages ring through.
+    pub fn silences_at(&self, options: &MessageOptions, now: DateTime<Utc>) -> bool {
+        !options.urgent && self.is_quiet_at(now)
+    }
+
+    /// Whether a message with `options` is held at `now`. Urgent messages
+    /// never are.
+    pub fn holds_at(&self, options: &MessageOptions, now: DateTime<Utc>) -> bool {
+        self.hold && self.silences_at(options, now)
+    }
+
+    /// The next end of the window after `now`.
+    pub fn end_after(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let mut date = now.with_timezone(&self.tz).date_naive();
+        loop {
+            // An end inside a DST gap moves an hour on, past the gap.
+            let local = date.and_time(self.end);
+            let end = self
+                .tz
+                .from_local_datetime(&local)
+                .earliest()
+                .or_else(|| {
+                    self.tz
+                        .from_local_datetime(&(local + TimeDelta::hours(1)))
+                        .earliest()
+                })
+                .map(|t| t.with_timezone(&Utc));
+            if let Some(end) = end.filter(|&e| e > now) {
+                return end;
+            }
+            date = date.succ_opt().unwrap_or(date);
+        }
+    }
+}
+
+/// Whether a message to `dest` goes out without a notification sound.
+pub fn is_silent(state: &AppState, dest: &Destination, options: &MessageOptions) -> bool {
+    options.silent
+        || (!options.urgent && state.mute.is_muted())
+        || dest
+            .quiet_hours
+            .as_ref()
+            .is_some_and(|q| q.silences_at(options, Utc::now()))
+}
+
+/// Whether a message to `dest` is to be held until its quiet hours, or a
+/// mute, end.
+pub fn should_hold(state: &AppState, dest: &Destination, options: &MessageOptions) -> bool {
+    (!options.urgent && state.mute.is_muted())
+        || dest
+            .quiet_hours
+            .as_ref()
+            .is_some_and(|q| q.holds_at(options, Utc::now()))
+}
+
+/// When a message held for `dest` now is released: once the mute ends, and
+/// then its quiet hours. `None` if that waits on `/unmute`.
+pub fn held_until(state: &AppState, dest: &Destination) -> Option<DateTime<Utc>> {
+    let after = match state.mute.until() {
+        Some(until) => until?,
+        None => Utc::now(),
+    };
+    match &dest.quiet_hours {
+        Some(q) if q.hold && q.is_quiet_at(after) => Some(q.end_after(after)),
+        _ => Some(after),
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+struct Muted {
+    /// `None` mutes until `/unmute`.
+    #[serde(default)]
+    until: Option<DateTime<Utc>>,
+}
+
+/// The mute set with the bot's `/mute` command, kept in
+/// `<data_dir>/mute.json`.
+pub struct Mute {
+    path: PathBuf,
+    muted: Mutex<Option<Muted>>,
+}
+
+impl Mute {
+    pub fn open(path: PathBuf) -> Result<Mute, BoxError> {
+        let muted: Option<Muted> = persist::load(&path)?;
+        Ok(Mute {
+            path,
+            muted: Mutex::new(muted),
+        })
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.until().is_some()
+    }
+
+    /// Mute until `until`, or until unmuted if `None`.
+    pub fn mute(&self, until: Option<DateTime<Utc>>) -> Result<(), BoxError> {
+        self.set(Some(Muted { until }))
+    }
+
+    pub fn unmute(&self) -> Result<(), BoxError> {
+        self.set(None)
+    }
+
+    fn set(&self, value: Option<Muted>) -> Result<(), BoxError> {
+        let mut muted = self.muted.lock().unwrap();
+        persist::save(&self.path, &value)?;
+        *muted = value;
+        Ok(())
+    }
+
+    /// When the mute ends: `Some(None)` if only `/unmute` ends it, `None`
+    /// if not muted.
+    pub fn until(&self) -> Option<Option<DateTime<Utc>>> {
+        let muted = *self.muted.lock().unwrap();
+        muted
+            .filter(|m| m.until.is_none_or(|t| t > Utc::now()))
+            .map(|m| m.until)
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct HeldMessage {
+    destination: String,
+    text: String,
+    #[serde(flatten)]
+    options: MessageOptions,
+    #[serde(default)]
+    overflow: Overflow,
+    /// Failed deliveries so far. The message stays held and is tried again
+    /// at `retry_at`.
+    #[serde(default)]
+    attempts: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retry_at: Option<DateTime<Utc>>,
+}
+
+/// Messages held for the end of quiet hours or a mute, in the order they
+/// came.
+pub struct Held {
+    path: PathBuf,
+    messages: Mutex<Vec<HeldMessage>>,
+}
+
+impl Held {
+    pub fn open(path: PathBuf) -> Result<Held, BoxError> {
+        let messages: Vec<HeldMessage> = persist::load(&path)?;
+        if !messages.is_empty() {
+            info!("loaded held messages", count = messages.len());
+        }
+        Ok(Held {
+            path,
+            messages: Mutex::new(messages),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.lock().unwrap().len()
+    }
+
+    pub fn hold(
+        &self,
+        destination: &str,
+        text: String,
+        options: MessageOptions,
+        overflow: Overflow,
+    ) -> Result<(), BoxError> {
+        let mut messages = self.messages.lock().unwrap();
+        messages.push(HeldMessage {
+            destination: destination.to_string(),
+            text,
+            options,
+            overflow,
+            attempts: 0,
+            retry_at: None,
+        });
+        if let Err(e) = persist::save(&self.path, &*messages) {
+            messages.pop();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn save(&self, messages: &[HeldMessage]) {
+        if let Err(e) = persist::save(&self.path, &messages) {
+            error!("failed to save held messages", error = e);
+        }
+    }
+
+    /// The first message whose destination's quiet hours, and the mute, are
+    /// over, with its index. It stays held until it is
+    /// [`done`](Held::done), so a failed send or a crash doesn't lose it.
+    /// Messages behind one waiting for a retry wait too, to keep their
+    /// order.
+    ///
+    /// Only the releaser removes messages, so the index stays valid until
+    /// then.
+    fn next_due(&self, state: &AppState) -> Option<(usize, Arc<Destination>, HeldMessage)> {
+        let mut messages = self.messages.lock().unwrap();
+        let count = messages.len();
+        messages.retain(|held| {
+            let known = state.destination(Some(&held.destination)).is_some();
+            if !known {
+                warn!(
+                    "dropping held message for unknown destination",
+                    destination = held.destination
+                );
+            }
+            known
+        });
+        if messages.len() != count {
+            self.save(&messages);
+        }
+
+        let now = Utc::now();
+        let mut waiting: Vec<&str> = Vec::new();
+        for (i, held) in messages.iter().enumerate() {
+            if waiting.contains(&held.destination.as_str()) {
+                continue;
+            }
+            if held.retry_at.is_some_and(|t| t > now) {
+                waiting.push(&held.destination);
+                continue;
+            }
+            let dest = state.destination(Some(&held.destination))?;
+            if !should_hold(state, &dest, &held.options) {
+                return Some((i, dest, held.clone()));
+            }
+        }
+        None
+    }
+
+    /// Remove the message at `index` once it is delivered or given up on.
+    fn done(&self, index: usize) {
+        let mut messages = self.messages.lock().unwrap();
+        if index < messages.len() {
+            messages.remove(index);
+            self.save(&messages);
+        }
+    }
+
+    /// Try the message at `index` again at `at`.
+    fn retry(&self, index: usize, attempts: u32, at: DateTime<Utc>) {
+        let mut messages = self.messages.lock().unwrap();
+        if let Some(held) = messages.get_mut(index) {
+            held.attempts = attempts;
+            held.retry_at = Some(at);
+            self.save(&messages);
+        }
+    }
+}
+
+/// Send held messages once their destination's quiet hours, and the mute,
+/// are over, retrying failed ones.
+pub async fn run_releaser(state: Arc<AppState>) {
+    let held = &state.held;
+    loop {
+        while let Some((index, dest, message)) = held.next_due(&state) {
+            info!("releasing held message", destination = dest.name);
+            let result = deliver(
+                &state,
+                &dest,
+                message.text,
+                message.options,
+                message.overflow,
+            )
+            .await;
+            let Err(e) = result else {
+                held.done(index);
+                continue;
+            };
+            let attempts = message.attempts + 1;
+            match e.retry_at(attempts) {
+                Some(at) => {
+                    warn!(
+                        "failed to send held message, retrying",
+                        destination = dest.name,
+                        attempts = attempts,
+                        retry_at = at.to_rfc3339_opts(SecondsFormat::Secs, true),
+                        error = e
+                    );
+                    held.retry(index, attempts, at);
+                }
+                None => {
+                    error!(
+                        "dropping held message",
+                        destination = dest.name,
+                        attempts = attempts,
+                        error = e
+                    );
+                    held.done(index);
+                }
+            }
+        }
+        tokio::time::sleep(RELEASE_TICK).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::priority::Priority;
+
+    fn quiet(start: &str, end: &str, timezone: Option<&str>, mode: QuietMode) -> QuietHours {
+        QuietHours::from_config(&QuietHoursConfig {
+            start: start.to_string(),
+            end: end.to_string(),
+            timezone: timezone.map(String::from),
+            mode,
+        })
+        .unwrap()
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().to_utc()
+    }
+
+    #[test]
+    fn overnight_window() {
+        let q = quiet("22:00", "07:00", None, QuietMode::Silent);
+        for (t, expected) in [
+            ("2026-10-15T12:00:00Z", false),
+            ("2026-10-15T21:59:59Z", false),
+            ("2026-10-15T22:00:00Z", true),
+            ("2026-10-15T23:30:00Z", true),
+            ("2026-10-16T00:00:00Z", true),
+            ("2026-10-16T03:00:00Z", true),
+            ("2026-10-16T06:59:59Z", true),
+            ("2026-10-16T07:00:00Z", false),
+        ] {
+            assert_eq!(q.is_quiet_at(utc(t)), expected, "{}", t);
+        }
+        // Before midnight the window ends the next day; after, the same day.
+        assert_eq!(
+            q.end_after(utc("2026-10-15T23:30:00Z")),
+            utc("2026-10-16T07:00:00Z")
+        );
+        assert_eq!(
+            q.end_after(utc("2026-10-16T03:00:00Z")),
+            utc("2026-10-16T07:00:00Z")
+        );
+        // The end itself is already past.
+        assert_eq!(
+            q.end_after(utc("2026-10-16T07:00:00Z")),
+            utc("2026-10-17T07:00:00Z")
+        );
+    }
+
+    #[test]
+    fn same_day_window() {
+        // 12:00 to 14:00 in New York is 16:00 to 18:00 UTC in October.
+        let q = quiet(
+            "12:00",
+            "14:00",
+            Some("America/New_York"),
+            QuietMode::Silent,
+        );
+        for (t, expected) in [
+            ("2026-10-15T15:59:00Z", false),
+            ("2026-10-15T16:00:00Z", true),
+            ("2026-10-15T17:59:00Z", true),
+            ("2026-10-15T18:00:00Z", false),
+            ("2026-10-16T03:00:00Z", false),
+        ] {
+            assert_eq!(q.is_quiet_at(utc(t)), expected, "{}", t);
+        }
+        assert_eq!(
+            q.end_after(utc("2026-10-15T16:30:00Z")),
+            utc("2026-10-15T18:00:00Z")
+        );
+    }
+
+    #[test]
+    fn end_in_spring_forward_gap() {
+        // Berlin skips from 02:00 to 03:00 on 2026-03-29; an end at 02:30
+        // moves an hour on, to 03:30 CEST.
+        let q = quiet("22:00", "02:30", Some("Europe/Berlin"), QuietMode::Hold);
+        assert_eq!(
+            q.end_after(utc("2026-03-28T23:00:00Z")),
+            utc("2026-03-29T01:30:00Z")
+        );
+        // The next day is back to normal.
+        assert_eq!(
+            q.end_after(utc("2026-03-29T21:00:00Z")),
+            utc("2026-03-30T00:30:00Z")
+        );
+    }
+
+    #[test]
+    fn end_in_fall_back_overlap() {
+        // 02:30 happens twice on 2026-10-25 in Berlin; the first one ends
+        // the window.
+        let q = quiet("22:00", "02:30", Some("Europe/Berlin"), QuietMode::Hold);
+        assert_eq!(
+            q.end_after(utc("2026-10-24T21:00:00Z")),
+            utc("2026-10-25T00:30:00Z")
+        );
+    }
+
+    #[test]
+    fn urgent_messages_ring_through() {
+        let hold = quiet("22:00", "07:00", None, QuietMode::Hold);
+        let silent = quiet("22:00", "07:00", None, QuietMode::Silent);
+        let night = utc("2026-10-15T23:00:00Z");
+        let day = utc("2026-10-15T12:00:00Z");
+
+        let normal = MessageOptions::default();
+        assert!(hold.holds_at(&normal, night));
+        assert!(!hold.holds_at(&normal, day));
+        assert!(!silent.holds_at(&normal, night));
+        assert!(silent.silences_at(&normal, night));
+        assert!(!silent.silences_at(&normal, day));
+
+        let urgent = MessageOptions {
+            urgent: Priority::High.is_urgent(),
+            ..Default::default()
+        };
+        assert!(!hold.holds_at(&urgent, night));
+        assert!(!silent.silences_at(&urgent, night));
+    }
+
+    #[test]
+    fn invalid_config() {
+        let config = |start: &str, end: &str, timezone: Option<&str>| QuietHoursConfig {
+            start: start.to_string(),
+            end: end.to_string(),
+            timezone: timezone.map(String::from),
+            mode: QuietMode::Silent,
+        };
+        assert!(QuietHours::from_config(&config("22:00", "22:00", None)).is_err());
+        assert!(QuietHours::from_config(&config("25:00", "07:00", None)).is_err());
+        assert!(QuietHours::from_config(&config("10pm", "07:00", None)).is_err());
+        assert!(QuietHours::from_config(&config("22:00", "07:00", Some("Mars/Base"))).is_err());
+    }
+}
@@ -0,0 +1,169 @@
+//! Client-side rate limiting so we stay under Telegram's flood limits
+//! instead of relying on 429s: roughly 30 messages/s per bot, 1 message/s
+//! per private chat and 20 messages/min per group or channel. Each bot
+//! token has its own limits.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::config::RateLimitConfig;
+
+/// Generic cell rate algorithm: a token bucket expressed as the
+/// theoretical arrival time of the next request.
+struct Gcra {
+    interval: Duration,
+    tolerance: Duration,
+    tat: Instant,
+}
+
+impl Gcra {
+    /// A bucket that is full at `now`.
+    fn new(per_sec: f64, burst: u32, now: Instant) -> Gcra {
+        let interval = if per_sec > 0.0 {
+            Duration::from_secs_f64(1.0 / per_sec)
+        } else {
+            Duration::ZERO
+        };
+        Gcra {
+            interval,
+            tolerance: interval * burst.saturating_sub(1),
+            tat: now,
+        }
+    }
+
+    /// Reserve a slot no earlier than `at` and return when it may be used.
+    fn reserve(&mut self, at: Instant) -> Instant {
+        let tat = self.tat.max(at);
+        let start = tat.checked_sub(self.tolerance).unwrap_or(at).max(at);
+        self.tat = tat + self.interval;
+        start
+    }
+}
+
+pub struct RateLimiter {
+    bot_per_sec: f64,
+    chat_per_sec: f64,
+    group_per_sec: f64,
+    /// The per-bot limit of each bot, by token.
+    bots: Mutex<HashMap<String, Gcra>>,
+    /// The per-chat limits, by bot token and chat id: each bot has its own.
+    chats: Mutex<HashMap<(String, i64), Gcra>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> RateLimiter {
+        RateLimiter {
+            bot_per_sec: config.global_per_sec,
+            chat_per_sec: config.chat_per_sec,
+            group_per_sec: config.group_per_min / 60.0,
+            bots: Mutex::new(HashMap::new()),
+            chats: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reserve a slot under the per-bot limit of `token`, no earlier than
+    /// `at`.
+    fn reserve_bot(&self, token: &str, at: Instant) -> Instant {
+        let mut bots = self.bots.lock().unwrap();
+        if !bots.contains_key(token) {
+            let burst = self.bot_per_sec.max(1.0) as u32;
+            bots.insert(token.to_string(), Gcra::new(self.bot_per_sec, burst, at));
+        }
+        bots.get_mut(token).unwrap().reserve(at)
+    }
+
+    /// Reserve a slot for a message from the bot `token` to `chat_id`.
+    /// Group and channel ids are negative and get the stricter group limit.
+    fn reserve(&self, token: &str, chat_id: i64, now: Instant) -> Instant {
+        let chat_start = {
+            let mut chats = self.chats.lock().unwrap();
+            let per_sec = if chat_id < 0 {
+                self.group_per_sec
+            } else {
+                self.chat_per_sec
+            };
+            chats
+                .entry((token.to_string(), chat_id))
+                .or_insert_with(|| Gcra::new(per_sec, 1, now))
+                .reserve(now)
+        };
+        self.reserve_bot(token, chat_start)
+    }
+
+    /// Wait until the bot `token` may send a message to `chat_id`. Returns
+    /// the time spent waiting.
+    pub async fn acquire(&self, token: &str, chat_id: i64) -> Duration {
+        let now = Instant::now();
+        let start = self.reserve(token, chat_id, now);
+        Self::wait_until(now, start).await
+    }
+
+    /// Wait for a slot under the per-bot limit only, for calls that are not
+    /// addressed to a chat.
+    pub async fn acquire_bot(&self, token: &str) -> Duration {
+        let now = Instant::now();
+        let start = self.reserve_bot(token, now);
+        Self::wait_until(now, start).await
+    }
+
+    async fn wait_until(now: Instant, start: Instant) -> Duration {
+        let wait = start.saturating_duration_since(now);
+        if !wait.is_zero() {
+            tokio::time::sleep_until(start).await;
+        }
+        wait
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(per_sec: f64) -> RateLimiter {
+        RateLimiter::new(&RateLimitConfig {
+            global_per_sec: per_sec,
+            chat_per_sec: 1000.0,
+            group_per_min: 60_000.0,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn bots_have_separate_limits() {
+        let limiter = limiter(1.0);
+        let now = Instant::now();
+        assert_eq!(limiter.reserve("a", 1, now), now);
+        assert_eq!(limiter.reserve("a", 2, now), now + Duration::from_secs(1));
+        // Another bot isn't held up by the first one's queue.
+        assert_eq!(limiter.reserve("b", 1, now), now);
+        assert_eq!(limiter.reserve_bot("b", now), now + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn bot_limit_allows_a_burst() {
+        let limiter = limiter(3.0);
+        let now = Instant::now();
+        for chat in 1..=3 {
+            assert_eq!(limiter.reserve("a", chat, now), now);
+        }
+        assert!(limiter.reserve("a", 4, now) > now);
+    }
+
+    #[test]
+    fn chat_limits_are_per_bot() {
+        let limiter = RateLimiter::new(&RateLimitConfig::default());
+        let now = Instant::now();
+        assert_eq!(limiter.reserve("a", 42, now), now);
+        assert_eq!(limiter.reserve("a", 42, now), now + Duration::from_secs(1));
+        assert_eq!(limiter.reserve("b", 42, now), now);
+        // Groups get 20 a minute.
+        assert_eq!(limiter.reserve("c", -100, now), now);
+        assert_eq!(
+            limiter.reserve("c", -100, now),
+            now + Duration::from_secs(3)
+        );
+    }
+}
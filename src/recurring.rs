@@ -0,0 +1,255 @@
+//! Recurring messages from `schedules` in the config: a cron expression,
+//! read in a time zone, and the text or template to send when it fires.
+//!
+//! The time of each schedule's last run is kept in
+//! `<data_dir>/schedules.json`. Runs missed while the relay was down are
+//! skipped, or with `missed: catch_up`, the latest one is sent on startup.
+//! A run that fails to send is retried until it goes out.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use chrono_tz::Tz;
+
+use crate::config::{MissedRuns, ScheduleConfig};
+use crate::cron::Cron;
+use crate::split::Overflow;
+use crate::telegram::{Destination, MessageOptions};
+use crate::template::Template;
+use crate::{deliver, parse_mode_name, persist, AppState, BoxError, DeliverError};
+
+/// How often schedules are checked.
+const TICK: Duration = Duration::from_secs(1);
+
+enum Content {
+    Text {
+        message: String,
+        parse_mode: Option<&'static str>,
+    },
+    /// The name of a `templates` entry.
+    Template(String),
+}
+
+struct Schedule {
+    name: String,
+    cron: Cron,
+    tz: Tz,
+    content: Content,
+    destination: Option<String>,
+    missed: MissedRuns,
+}
+
+pub struct Schedules {
+    path: PathBuf,
+    schedules: Vec<Schedule>,
+    /// The time of each schedule's last run, by name.
+    last_runs: Mutex<BTreeMap<String, DateTime<Utc>>>,
+}
+
+impl Schedules {
+    pub fn open(
+        path: PathBuf,
+        configs: &BTreeMap<String, ScheduleConfig>,
+        templates: &HashMap<String, Template>,
+        destinations: &HashMap<String, Arc<Destination>>,
+    ) -> Result<Schedules, BoxError> {
+        let mut schedules = Vec::new();
+        for (name, c) in configs {
+            let err = |e: String| format!("schedule {}: {}", name, e);
+            let cron = Cron::parse(&c.cron).map_err(|e| err(format!("cron: {}", e)))?;
+            let tz = match c.timezone.as_deref() {
+                Some(tz) => tz
+                    .parse()
+                    .map_err(|_| err(format!("unknown time zone {:?}", tz)))?,
+                None => Tz::UTC,
+            };
+            let content = match (&c.message, &c.template) {
+                (Some(message), None) => Content::Text {
+                    message: message.clone(),
+                    parse_mode: c.parse_mode.as_deref().and_then(parse_mode_name),
+                },
+                (None, Some(t)) if templates.contains_key(t) => Content::Template(t.clone()),
+                (None, Some(t)) => return Err(err(format!("unknown template {}", t)).into()),
+                _ => return Err(err("set message or template".to_string()).into()),
+            };
+            if let Some(d) = c
+                .destination
+                .as_deref()
+                .filter(|d| !destinations.contains_key(*d))
+            {
+                return Err(err(format!("unknown destination {}", d)).into());
+            }
+            if cron.next_after(Utc::now(), tz).is_none() {
+                return Err(err(format!("{:?} never fires", c.cron)).into());
+            }
+            schedules.push(Schedule {
+                name: name.clone(),
+                cron,
+                tz,
+                content,
+                destination: c.destination.clone(),
+                missed: c.missed,
+            });
+        }
+        let last_runs = persist::load(&path)?;
+        Ok(Schedules {
+            path,
+            schedules,
+            last_runs: Mutex::new(last_runs),
+        })
+    }
+}
+
+/// Render what `schedule` sends for its run at `at`.
+fn render(
+    state: &AppState,
+    schedule: &Schedule,
+    at: DateTime<Utc>,
+) -> Result<(String, Option<&'static str>), String> {
+    let (template, parse_mode): (&Template, _) = match &schedule.content {
+        Content::Text {
+            message,
+            parse_mode,
+        } => return Ok((message.clone(), *parse_mode)),
+        Content::Template(name) => {
+            let t = state
+                .templates
+                .get(name)
+                .ok_or_else(|| format!("unknown template {}", name))?;
+            (t, t.parse_mode)
+        }
+    };
+    let local = at.with_timezone(&schedule.tz);
+    let ctx = serde_json::json!({
+        "schedule": schedule.name,
+        "time": local.to_rfc3339(),
+        "date": local.format("%Y-%m-%d").to_string(),
+        "weekday": local.format("%A").to_string(),
+    });
+    Ok((template.render(&ctx)?, parse_mode))
+}
+
+/// Send `schedule`'s message for its run at `at`. A run whose template
+/// fails has nothing to send and counts as done.
+async fn fire(
+    state: &AppState,
+    schedule: &Schedule,
+    at: DateTime<Utc>,
+) -> Result<(), DeliverError> {
+    let Some(dest) = state.destination(schedule.destination.as_deref()) else {
+        return Ok(());
+    };
+    let (message, parse_mode) = match render(state, schedule, at) {
+        Ok(m) => m,
+        Err(e) => {
+            warn!("template failed", schedule = schedule.name, error = e);
+            return Ok(());
+        }
+    };
+    if message.is_empty() {
+        return Ok(());
+    }
+    info!("sending scheduled reminder", schedule = schedule.name);
+    let options = MessageOptions {
+        parse_mode: parse_mode.map(String::from),
+        ..Default::default()
+    };
+    deliver(state, &dest, message, options, Overflow::default())
+        .await
+        .map(|_| ())
+}
+
+/// When a schedule runs next.
+struct Next {
+    at: Option<DateTime<Utc>>,
+    /// A run that failed to send and is being retried, and how many times
+    /// it failed.
+    failed: Option<(DateTime<Utc>, u32)>,
+}
+
+/// Send each schedule's message when its expression fires. A run that
+/// fails to send is retried with backoff until it is sent, and only then
+/// recorded as the schedule's last run.
+pub async fn run(state: Arc<AppState>) {
+    let schedules = &state.schedules;
+    if schedules.schedules.is_empty() {
+        return;
+    }
+
+    // With catch-up, the search for the next run starts at the last one,
+    // so a run missed while the relay was down comes due right away.
+    let now = Utc::now();
+    let mut next: Vec<Next> = {
+        let last_runs = schedules.last_runs.lock().unwrap();
+        schedules
+            .schedules
+            .iter()
+            .map(|s| {
+                let from = match (s.missed, last_runs.get(&s.name)) {
+                    (MissedRuns::CatchUp, Some(&last)) => last.min(now),
+                    _ => now,
+                };
+                Next {
+                    at: s.cron.next_after(from, s.tz),
+                    failed: None,
+                }
+            })
+            .collect()
+    };
+
+    loop {
+        let now = Utc::now();
+        for (schedule, next) in schedules.schedules.iter().zip(next.iter_mut()) {
+            let Some(due) = next.at.filter(|t| *t <= now) else {
+                continue;
+            };
+            let (mut at, mut attempts) = next.failed.unwrap_or((due, 0));
+            // Missed runs are collapsed into the latest one, also while
+            // an earlier one is being retried.
+            while let Some(t) = schedule
+                .cron
+                .next_after(at, schedule.tz)
+                .filter(|t| *t <= now)
+            {
+                at = t;
+                attempts = 0;
+            }
+
+            if let Err(e) = fire(&state, schedule, at).await {
+                attempts += 1;
+                match e.retry_at(attempts) {
+                    Some(retry_at) => {
+                        warn!(
+                            "failed to send scheduled reminder, retrying",
+                            schedule = schedule.name,
+                            attempts = attempts,
+                            retry_at = retry_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+                            error = e
+                        );
+                        next.at = Some(retry_at);
+                        next.failed = Some((at, attempts));
+                        continue;
+                    }
+                    None => error!(
+                        "dropping scheduled reminder",
+                        schedule = schedule.name,
+                        attempts = attempts,
+                        error = e
+                    ),
+                }
+            }
+            next.at = schedule.cron.next_after(now, schedule.tz);
+            next.failed = None;
+
+            let mut last_runs = schedules.last_runs.lock().unwrap();
+            last_runs.insert(schedule.name.clone(), at);
+            if let Err(e) = persist::save(&schedules.path, &*last_runs) {
+                error!("failed to save schedules", error = e);
+            }
+        }
+        tokio::time::sleep(TICK).await;
+    }
+}
@@ -0,0 +1,313 @@
+//! Messages sent with `send_at` or `delay`, kept in
+//! `<data_dir>/scheduled.json` until they are delivered. When due they go
+//! out like any `POST /` message, and failed sends are retried with
+//! backoff. `GET /scheduled` lists a destination's pending messages,
+//! `GET`/`DELETE /scheduled/<id>` shows or cancels one.
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::split::Overflow;
+use crate::telegram::{Destination, MessageOptions};
+use crate::{deliver, json_response, persist, AppState, BoxError};
+
+/// How often the schedule is checked for due messages.
+const TICK: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Entry {
+    id: u64,
+    destination: String,
+    send_at: DateTime<Utc>,
+    created_at: DateTime<Utc>,
+    text: String,
+    #[serde(flatten)]
+    options: MessageOptions,
+    #[serde(default)]
+    overflow: Overflow,
+    /// Failed deliveries so far. The message stays scheduled and is tried
+    /// again at `retry_at`.
+    #[serde(default)]
+    attempts: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retry_at: Option<DateTime<Utc>>,
+}
+
+impl Entry {
+    fn due_at(&self) -> DateTime<Utc> {
+        self.retry_at.unwrap_or(self.send_at)
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "id": self.id,
+            "send_at": self.send_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+            "created_at": self.created_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+            "message": self.text,
+        })
+    }
+}
+
+/// The contents of `scheduled.json`. `next_id` is kept so ids aren't
+/// reused once the schedule empties.
+#[derive(Deserialize, Serialize)]
+struct Saved {
+    next_id: u64,
+    messages: Vec<Entry>,
+}
+
+impl Default for Saved {
+    fn default() -> Saved {
+        Saved {
+            next_id: 1,
+            messages: Vec::new(),
+        }
+    }
+}
+
+pub struct Schedule {
+    path: PathBuf,
+    inner: Mutex<Saved>,
+}
+
+impl Schedule {
+    pub fn open(path: PathBuf) -> Result<Schedule, BoxError> {
+        let saved: Saved = persist::load(&path)?;
+        if !saved.messages.is_empty() {
+            info!("loaded scheduled messages", count = saved.messages.len());
+        }
+        Ok(Schedule {
+            path,
+            inner: Mutex::new(saved),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().messages.len()
+    }
+
+    /// Keep `text` for delivery at `send_at`. Returns its id.
+    pub fn add(
+        &self,
+        destination: &str,
+        send_at: DateTime<Utc>,
+        text: String,
+        options: MessageOptions,
+        overflow: Overflow,
+    ) -> Result<u64, BoxError> {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.messages.push(Entry {
+            id,
+            destination: destination.to_string(),
+            send_at,
+            created_at: Utc::now(),
+            text,
+            options,
+            overflow,
+            attempts: 0,
+            retry_at: None,
+        });
+        if let Err(e) = persist::save(&self.path, &*inner) {
+            inner.messages.pop();
+            return Err(e);
+        }
+        Ok(id)
+    }
+
+    /// The earliest message that is due. It stays scheduled until it is
+    /// [`done`](Schedule::done), so a failed send or a crash doesn't lose it.
+    fn next_due(&self) -> Option<Entry> {
+        let now = Utc::now();
+        let inner = self.inner.lock().unwrap();
+        inner
+            .messages
+            .iter()
+            .filter(|e| e.due_at() <= now)
+            .min_by_key(|e| (e.due_at(), e.id))
+            .cloned()
+    }
+
+    /// Remove message `id` once it is delivered or given up on.
+    fn done(&self, id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let count = inner.messages.len();
+        inner.messages.retain(|e| e.id != id);
+        if inner.messages.len() == count {
+            return;
+        }
+        if let Err(e) = persist::save(&self.path, &*inner) {
+            error!("failed to save scheduled messages", error = e);
+        }
+    }
+
+    /// Try message `id` again at `at`.
+    fn retry(&self, id: u64, attempts: u32, at: DateTime<Utc>) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(entry) = inner.messages.iter_mut().find(|e| e.id == id) else {
+            return;
+        };
+        entry.attempts = attempts;
+        entry.retry_at = Some(at);
+        if let Err(e) = persist::save(&self.path, &*inner) {
+            error!("failed to save scheduled messages", error = e);
+        }
+    }
+}
+
+/// `POST /` with `send_at` or `delay`: schedule the message.
+pub fn schedule(
+    state: &AppState,
+    dest: &Destination,
+    send_at: DateTime<Utc>,
+    message: String,
+    options: MessageOptions,
+    overflow: Overflow,
+) -> Result<Response<Full<Bytes>>, BoxError> {
+    match state
+        .schedule
+        .add(&dest.name, send_at, message, options, overflow)
+    {
+        Ok(id) => json_response(
+            StatusCode::ACCEPTED,
+            serde_json::json!({
+                "status": "scheduled",
+                "id": id,
+                "send_at": send_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+                "chat_id": dest.chat_id,
+            }),
+        ),
+        Err(e) => json_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            serde_json::json!({"error": format!("failed to schedule message: {}", e)}),
+        ),
+    }
+}
+
+/// The position of message `id` to `dest`.
+fn find(messages: &[Entry], dest: &Destination, id: &str) -> Option<usize> {
+    let id: u64 = id.parse().ok()?;
+    messages
+        .iter()
+        .position(|e| e.id == id && e.destination == dest.name)
+}
+
+fn not_found(id: &str) -> Result<Response<Full<Bytes>>, BoxError> {
+    json_response(
+        StatusCode::NOT_FOUND,
+        serde_json::json!({"error": format!("no scheduled message {}", id)}),
+    )
+}
+
+/// `GET /scheduled` lists the destination's pending messages, soonest
+/// first; `GET /scheduled/<id>` shows one.
+pub fn handle_get(
+    state: &AppState,
+    dest: &Destination,
+    id: Option<&str>,
+) -> Result<Response<Full<Bytes>>, BoxError> {
+    let schedule = &state.schedule;
+    let inner = schedule.inner.lock().unwrap();
+
+    let Some(id) = id else {
+        let mut messages: Vec<&Entry> = inner
+            .messages
+            .iter()
+            .filter(|e| e.destination == dest.name)
+            .collect();
+        messages.sort_by_key(|e| (e.send_at, e.id));
+        let messages: Vec<serde_json::Value> = messages.iter().map(|e| e.to_json()).collect();
+        return json_response(StatusCode::OK, serde_json::json!({"scheduled": messages}));
+    };
+    match find(&inner.messages, dest, id) {
+        Some(i) => json_response(StatusCode::OK, inner.messages[i].to_json()),
+        None => not_found(id),
+    }
+}
+
+/// `DELETE /scheduled/<id>`: cancel a pending message.
+pub fn handle_cancel(
+    state: &AppState,
+    dest: &Destination,
+    id: &str,
+) -> Result<Response<Full<Bytes>>, BoxError> {
+    let schedule = &state.schedule;
+    let mut inner = schedule.inner.lock().unwrap();
+    let Some(index) = find(&inner.messages, dest, id) else {
+        return not_found(id);
+    };
+    let entry = inner.messages.remove(index);
+    if let Err(e) = persist::save(&schedule.path, &*inner) {
+        inner.messages.insert(index, entry);
+        return json_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            serde_json::json!({"error": format!("failed to cancel message: {}", e)}),
+        );
+    }
+    json_response(
+        StatusCode::OK,
+        serde_json::json!({"status": "cancelled", "id": entry.id}),
+    )
+}
+
+/// Deliver scheduled messages as they come due, retrying failed ones.
+pub async fn run_sender(state: Arc<AppState>) {
+    let schedule = &state.schedule;
+    loop {
+        while let Some(entry) = schedule.next_due() {
+            let Some(dest) = state.destination(Some(&entry.destination)) else {
+                warn!(
+                    "dropping scheduled message for unknown destination",
+                    id = entry.id,
+                    destination = entry.destination
+                );
+                schedule.done(entry.id);
+                continue;
+            };
+            info!("sending scheduled message", id = entry.id);
+            let result = deliver(
+                &state,
+                &dest,
+                entry.text.clone(),
+                entry.options.clone(),
+                entry.overflow,
+            )
+            .await;
+            let Err(e) = result else {
+                schedule.done(entry.id);
+                continue;
+            };
+            let attempts = entry.attempts + 1;
+            match e.retry_at(attempts) {
+                Some(at) => {
+                    warn!(
+                        "failed to send scheduled message, retrying",
+                        id = entry.id,
+                        attempts = attempts,
+                        retry_at = at.to_rfc3339_opts(SecondsFormat::Secs, true),
+                        error = e
+                    );
+                    schedule.retry(entry.id, attempts, at);
+                }
+                None => {
+                    error!(
+                        "dropping scheduled message",
+                        id = entry.id,
+                        attempts = attempts,
+                        error = e
+                    );
+                    schedule.done(entry.id);
+                }
+            }
+        }
+        tokio::time::sleep(TICK).await;
+    }
+}
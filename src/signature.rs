@@ -0,0 +1,294 @@
+//! HMAC-SHA256 signature verification for inbound webhooks.
+//!
+//! Supported schemes:
+//! - `github`: `X-Hub-Signature-256: sha256=<hex>` over the body.
+//! - `generic`: `X-Signature: sha256=<hex>` (prefix optional) over the body.
+//! - `timestamped`: `X-Signature: t=<unix>,v1=<hex>` over `<t>.<body>`,
+//!   Stripe style. Signatures outside the tolerance window are rejected, and
+//!   so is a signature seen before within it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use hyper::HeaderMap;
+use ring::hmac;
+
+use crate::config::{SignatureConfig, SignatureScheme};
+use crate::util::now_secs;
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+pub struct Verifier {
+    routes: BTreeMap<String, SignatureConfig>,
+    /// Timestamped signatures already accepted, with the time they stop
+    /// being valid anyway.
+    seen: Mutex<HashMap<Vec<u8>, u64>>,
+}
+
+impl Verifier {
+    pub fn new(routes: BTreeMap<String, SignatureConfig>) -> Verifier {
+        Verifier {
+            routes,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether requests to `route` must be signed.
+    pub fn requires(&self, route: &str) -> bool {
+        self.routes.contains_key(route)
+    }
+
+    /// Verify the signature on a request to `route`. Routes without a
+    /// configured secret always pass.
+    pub fn verify(&self, route: &str, headers: &HeaderMap, body: &[u8]) -> Result<(), String> {
+        let config = match self.routes.get(route) {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        let header_name = config.header.as_deref().unwrap_or(match config.scheme {
+            SignatureScheme::Github => "x-hub-signature-256",
+            SignatureScheme::Generic | SignatureScheme::Timestamped => "x-signature",
+        });
+        let value = headers
+            .get(header_name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::trim)
+            .ok_or_else(|| format!("missing {} header", header_name))?;
+        let key = hmac::Key::new(hmac::HMAC_SHA256, config.secret.as_bytes());
+
+        match config.scheme {
+            SignatureScheme::Github | SignatureScheme::Generic => {
+                let hex = value.strip_prefix("sha256=").unwrap_or(value);
+                let tag = decode_hex(hex).ok_or("malformed signature")?;
+                hmac::verify(&key, body, &tag).map_err(|_| "signature mismatch".to_string())
+            }
+            SignatureScheme::Timestamped => {
+                let mut timestamp = None;
+                let mut tags = Vec::new();
+                for item in value.split(',') {
+                    match item.trim().split_once('=') {
+                        Some(("t", t)) => timestamp = t.parse::<u64>().ok(),
+                        Some(("v1", v)) => tags.extend(decode_hex(v)),
+                        _ => {}
+                    }
+                }
+                let timestamp = timestamp.ok_or("missing signature timestamp")?;
+                let now = now_secs();
+                if now.abs_diff(timestamp) > config.tolerance_secs {
+                    return Err("signature timestamp outside tolerance".to_string());
+                }
+
+                let mut signed = format!("{}.", timestamp).into_bytes();
+                signed.extend_from_slice(body);
+                let tag = tags
+                    .into_iter()
+                    .find(|tag| hmac::verify(&key, &signed, tag).is_ok())
+                    .ok_or("signature mismatch")?;
+
+                let mut seen = self.seen.lock().unwrap();
+                seen.retain(|_, expires| *expires >= now);
+                if seen.contains_key(&tag) {
+                    return Err("signature already used".to_string());
+                }
+                seen.insert(tag, timestamp + config.tolerance_secs);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "s3cret";
+    const BODY: &[u8] = b"{\"text\":\"deployed\"}";
+
+    fn verifier(scheme: SignatureScheme) -> Verifier {
+        let config = SignatureConfig {
+            secret: SECRET.to_string(),
+            scheme,
+            header: None,
+            tolerance_secs: 300,
+        };
+        Verifier::new(BTreeMap::from([("hook".to_string(), config)]))
+    }
+
+    fn sign(data: &[u8]) -> String {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, SECRET.as_bytes());
+        hmac::sign(&key, data)
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    fn stamp(t: u64, body: &[u8]) -> String {
+        let mut signed = format!("{}.", t).into_bytes();
+        signed.extend_from_slice(body);
+        format!("t={},v1={}", t, sign(&signed))
+    }
+
+    fn headers(name: &str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            hyper::header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+            value.parse().unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn unsigned_routes_pass() {
+        let v = verifier(SignatureScheme::Github);
+        assert!(!v.requires("message"));
+        assert!(v.verify("message", &HeaderMap::new(), BODY).is_ok());
+    }
+
+    #[test]
+    fn github() {
+        let v = verifier(SignatureScheme::Github);
+        let good = format!("sha256={}", sign(BODY));
+        assert!(v
+            .verify("hook", &headers("x-hub-signature-256", &good), BODY)
+            .is_ok());
+        // The same signature may be sent again: GitHub redelivers.
+        assert!(v
+            .verify("hook", &headers("x-hub-signature-256", &good), BODY)
+            .is_ok());
+        assert_eq!(
+            v.verify("hook", &headers("x-hub-signature-256", &good), b"{}"),
+            Err("signature mismatch".to_string())
+        );
+        assert_eq!(
+            v.verify("hook", &HeaderMap::new(), BODY),
+            Err("missing x-hub-signature-256 header".to_string())
+        );
+        assert_eq!(
+            v.verify("hook", &headers("x-hub-signature-256", "sha256=zz"), BODY),
+            Err("malformed signature".to_string())
+        );
+    }
+
+    #[test]
+    fn generic() {
+        let v = verifier(SignatureScheme::Generic);
+        let hex = sign(BODY);
+        assert!(v
+            .verify("hook", &headers("x-signature", &hex), BODY)
+            .is_ok());
+        let prefixed = format!("sha256={}", hex);
+        assert!(v
+            .verify("hook", &headers("x-signature", &prefixed), BODY)
+            .is_ok());
+
+        let mut tampered = BODY.to_vec();
+        tampered[3] ^= 1;
+        assert_eq!(
+            v.verify("hook", &headers("x-signature", &hex), &tampered),
+            Err("signature mismatch".to_string())
+        );
+        assert_eq!(
+            v.verify("hook", &HeaderMap::new(), BODY),
+            Err("missing x-signature header".to_string())
+        );
+    }
+
+    #[test]
+    fn custom_header() {
+        let config = SignatureConfig {
+            secret: SECRET.to_string(),
+            scheme: SignatureScheme::Generic,
+            header: Some("x-custom-sig".to_string()),
+            tolerance_secs: 300,
+        };
+        let v = Verifier::new(BTreeMap::from([("hook".to_string(), config)]));
+        let hex = sign(BODY);
+        assert!(v
+            .verify("hook", &headers("x-custom-sig", &hex), BODY)
+            .is_ok());
+        assert!(v
+            .verify("hook", &headers("x-signature", &hex), BODY)
+            .is_err());
+    }
+
+    #[test]
+    fn timestamped() {
+        let v = verifier(SignatureScheme::Timestamped);
+        let value = stamp(now_secs(), BODY);
+        assert!(v
+            .verify("hook", &headers("x-signature", &value), BODY)
+            .is_ok());
+
+        let value = stamp(now_secs() - 1, BODY);
+        assert_eq!(
+            v.verify("hook", &headers("x-signature", &value), b"{}"),
+            Err("signature mismatch".to_string())
+        );
+        assert_eq!(
+            v.verify("hook", &HeaderMap::new(), BODY),
+            Err("missing x-signature header".to_string())
+        );
+        let untimed = format!("v1={}", sign(BODY));
+        assert_eq!(
+            v.verify("hook", &headers("x-signature", &untimed), BODY),
+            Err("missing signature timestamp".to_string())
+        );
+    }
+
+    #[test]
+    fn timestamped_signature_covers_the_timestamp() {
+        let v = verifier(SignatureScheme::Timestamped);
+        let t = now_secs();
+        let value = stamp(t, BODY);
+        let moved = value.replacen(&format!("t={}", t), &format!("t={}", t + 1), 1);
+        assert_eq!(
+            v.verify("hook", &headers("x-signature", &moved), BODY),
+            Err("signature mismatch".to_string())
+        );
+    }
+
+    #[test]
+    fn timestamped_outside_tolerance() {
+        let v = verifier(SignatureScheme::Timestamped);
+        for t in [now_secs() - 301, now_secs() + 301] {
+            let value = stamp(t, BODY);
+            assert_eq!(
+                v.verify("hook", &headers("x-signature", &value), BODY),
+                Err("signature timestamp outside tolerance".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn timestamped_replay_is_rejected() {
+        let v = verifier(SignatureScheme::Timestamped);
+        let value = stamp(now_secs(), BODY);
+        assert!(v
+            .verify("hook", &headers("x-signature", &value), BODY)
+            .is_ok());
+        assert_eq!(
+            v.verify("hook", &headers("x-signature", &value), BODY),
+            Err("signature already used".to_string())
+        );
+    }
+
+    #[test]
+    fn timestamped_accepts_any_matching_v1() {
+        let v = verifier(SignatureScheme::Timestamped);
+        let t = now_secs();
+        let good = stamp(t, BODY).replace(&format!("t={},", t), "");
+        let value = format!("t={},v1={},{}", t, "00".repeat(32), good);
+        assert!(v
+            .verify("hook", &headers("x-signature", &value), BODY)
+            .is_ok());
+    }
+}
@@ -0,0 +1,268 @@
+//! `POST /slack`: Slack incoming-webhook compatibility. `text`, `blocks`
+//! and `attachments` are rendered into one Telegram HTML message, with
+//! mrkdwn converted along the way.
+
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::{Request, Response, StatusCode};
+use serde_json::Value;
+
+use crate::markup::{to_html, Dialect};
+use crate::telegram::{Destination, MessageOptions};
+use crate::util::{h, link, str_at};
+use crate::{deliver_message, json_response, overflow_header, AppState, BoxError};
+
+/// A composition object: `{"type": "mrkdwn" | "plain_text", "text": ...}`.
+fn text_object(v: &Value) -> String {
+    let text = str_at(v, "/text");
+    match str_at(v, "/type") {
+        "plain_text" => h(text),
+        _ => to_html(text, Dialect::Slack),
+    }
+}
+
+/// Flatten a `rich_text` element tree into HTML, keeping links and the
+/// basic text styles.
+fn rich_text(v: &Value, out: &mut String) {
+    match str_at(v, "/type") {
+        "text" => {
+            let mut text = h(str_at(v, "/text"));
+            for (style, tag) in [
+                ("bold", "b"),
+                ("italic", "i"),
+                ("strike", "s"),
+                ("code", "code"),
+            ] {
+                if v["style"][style].as_bool() == Some(true) {
+                    text = format!("<{}>{}</{}>", tag, text, tag);
+                }
+            }
+            out.push_str(&text);
+        }
+        "link" => {
+            let url = str_at(v, "/url");
+            let text = match str_at(v, "/text") {
+                "" => url,
+                t => t,
+            };
+            out.push_str(&link(url, text));
+        }
+        "user" => out.push_str(&h(&format!("@{}", str_at(v, "/user_id")))),
+        "channel" => out.push_str(&h(&format!("#{}", str_at(v, "/channel_id")))),
+        "emoji" => out.push_str(&h(&format!(":{}:", str_at(v, "/name")))),
+        "rich_text_preformatted" => {
+            let mut inner = String::new();
+            for e in v["elements"].as_array().into_iter().flatten() {
+                rich_text(e, &mut inner);
+            }
+            out.push_str(&format!("<pre>{}</pre>\n", inner));
+        }
+        "rich_text_list" => {
+            for item in v["elements"].as_array().into_iter().flatten() {
+                out.push_str("• ");
+                rich_text(item, out);
+                out.push('\n');
+            }
+        }
+        _ => {
+            for e in v["elements"].as_array().into_iter().flatten() {
+                rich_text(e, out);
+            }
+        }
+    }
+}
+
+fn render_block(block: &Value) -> Option<String> {
+    let elements = block["elements"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or(&[]);
+    let html = match str_at(block, "/type") {
+        "header" => format!("<b>{}</b>", h(str_at(block, "/text/text"))),
+        "section" => {
+            let mut parts = Vec::new();
+            if block["text"].is_object() {
+                parts.push(text_object(&block["text"]));
+            }
+            for field in block["fields"].as_array().into_iter().flatten() {
+                parts.push(text_object(field));
+            }
+            if let Some(url) = block["accessory"]["url"].as_str() {
+                parts.push(link(url, str_at(block, "/accessory/text/text")));
+            }
+            parts.join("\n")
+        }
+        "context" => {
+            let texts: Vec<String> = elements
+                .iter()
+                .filter(|e| e["text"].is_string())
+                .map(text_object)
+                .collect();
+            format!("<i>{}</i>", texts.join(" · "))
+        }
+        "divider" => "———".to_string(),
+        "image" => {
+            let title = match str_at(block, "/title/text") {
+                "" => str_at(block, "/alt_text"),
+                t => t,
+            };
+            link(
+                str_at(block, "/image_url"),
+                if title.is_empty() { "image" } else { title },
+            )
+        }
+        "actions" => {
+            let links: Vec<String> = elements
+                .iter()
+                .filter_map(|e| Some(link(e["url"].as_str()?, str_at(e, "/text/text"))))
+                .collect();
+            links.join(" | ")
+        }
+        "rich_text" => {
+            let mut out = String::new();
+            rich_text(block, &mut out);
+            out.trim_end().to_string()
+        }
+        _ => return None,
+    };
+    Some(html).filter(|s| !s.trim().is_empty())
+}
+
+fn color_emoji(color: &str) -> &'static str {
+    match color {
+        "good" => "🟢 ",
+        "warning" => "🟡 ",
+        "danger" => "🔴 ",
+        _ => "",
+    }
+}
+
+fn render_attachment(a: &Value) -> String {
+    let mut lines = Vec::new();
+    let pretext = str_at(a, "/pretext");
+    if !pretext.is_empty() {
+        lines.push(to_html(pretext, Dialect::Slack));
+    }
+    let author = str_at(a, "/author_name");
+    if !author.is_empty() {
+        lines.push(format!("<i>{}</i>", h(author)));
+    }
+    let title = str_at(a, "/title");
+    if !title.is_empty() {
+        let title = match str_at(a, "/title_link") {
+            "" => h(title),
+            url => link(url, title),
+        };
+        lines.push(format!(
+            "{}<b>{}</b>",
+            color_emoji(str_at(a, "/color")),
+            title
+        ));
+    }
+    let text = str_at(a, "/text");
+    if !text.is_empty() {
+        lines.push(to_html(text, Dialect::Slack));
+    }
+    for field in a["fields"].as_array().into_iter().flatten() {
+        lines.push(format!(
+            "<b>{}</b>: {}",
+            h(str_at(field, "/title")),
+            to_html(str_at(field, "/value"), Dialect::Slack)
+        ));
+    }
+    lines.extend(
+        a["blocks"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(render_block),
+    );
+    if let Some(url) = a["image_url"].as_str() {
+        lines.push(link(url, "image"));
+    }
+    let footer = str_at(a, "/footer");
+    if !footer.is_empty() {
+        lines.push(format!("<i>{}</i>", h(footer)));
+    }
+    if lines.is_empty() {
+        // Only a fallback, meant for clients that can't show the rest.
+        lines.push(h(str_at(a, "/fallback")));
+    }
+    lines.join("\n")
+}
+
+/// Render a Slack message payload. `text` is only a fallback for
+/// notifications when there are blocks, so it is skipped then.
+fn render(payload: &Value) -> String {
+    let mut sections = Vec::new();
+    let blocks: Vec<String> = payload["blocks"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(render_block)
+        .collect();
+    if blocks.is_empty() {
+        let text = str_at(payload, "/text");
+        if !text.is_empty() {
+            let html = match payload["mrkdwn"].as_bool() {
+                Some(false) => h(text),
+                _ => to_html(text, Dialect::Slack),
+            };
+            sections.push(html);
+        }
+    } else {
+        sections.push(blocks.join("\n"));
+    }
+    for a in payload["attachments"].as_array().into_iter().flatten() {
+        sections.push(render_attachment(a));
+    }
+    sections
+        .into_iter()
+        .filter(|s| !s.trim().is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+pub async fn handle(
+    req: Request<Bytes>,
+    state: &AppState,
+    dest: &Destination,
+) -> Result<Response<Full<Bytes>>, BoxError> {
+    let overflow = overflow_header(req.headers());
+    let is_form = req
+        .headers()
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|ct| ct.starts_with("application/x-www-form-urlencoded"));
+    // Legacy integrations post the JSON as a `payload` form field.
+    let json = if is_form {
+        form_urlencoded::parse(req.body())
+            .find(|(k, _)| k == "payload")
+            .map(|(_, v)| v.into_owned().into_bytes())
+            .unwrap_or_default()
+    } else {
+        req.body().to_vec()
+    };
+    let payload: Value = match serde_json::from_slice(&json) {
+        Ok(v) => v,
+        Err(e) => {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                serde_json::json!({"error": format!("invalid JSON: {}", e)}),
+            );
+        }
+    };
+
+    let message = render(&payload);
+    if message.trim().is_empty() {
+        return json_response(
+            StatusCode::BAD_REQUEST,
+            serde_json::json!({"error": "no_text"}),
+        );
+    }
+    let options = MessageOptions {
+        parse_mode: Some("HTML".to_string()),
+        ..Default::default()
+    };
+    deliver_message(state, dest, message, options, overflow).await
+}
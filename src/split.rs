@@ -0,0 +1,609 @@
+//! Fitting long texts into Telegram's 4096-character message limit.
+//!
+//! Telegram counts UTF-16 code units. We count the raw text including
+//! markup, which over-estimates formatted messages slightly but never
+//! produces a part Telegram would reject for length.
+
+use serde::{Deserialize, Serialize};
+
+/// Maximum message length accepted by `sendMessage`.
+pub const MAX_MESSAGE_LEN: usize = 4096;
+
+/// Maximum caption length for photos and documents.
+pub const MAX_CAPTION_LEN: usize = 1024;
+
+/// Room kept free in every part for the "(1/3)" label.
+const LABEL_RESERVE: usize = 16;
+
+/// What to do with a message longer than [`MAX_MESSAGE_LEN`], picked with
+/// the `telegram-overflow` header.
+#[derive(Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Overflow {
+    /// Send numbered parts, split on line boundaries where possible.
+    #[default]
+    Split,
+    /// Send only the first part, ending with an ellipsis.
+    Truncate,
+    /// Send the whole text as a `.txt` document.
+    Document,
+}
+
+impl Overflow {
+    pub fn from_header(value: &str) -> Option<Overflow> {
+        match value.trim().to_lowercase().as_str() {
+            "split" => Some(Overflow::Split),
+            "truncate" => Some(Overflow::Truncate),
+            "document" => Some(Overflow::Document),
+            _ => None,
+        }
+    }
+}
+
+/// Fit `message` into messages as `overflow` says. Returns the parts, and
+/// whether the one part is to be sent as a document.
+pub fn fit(message: String, parse_mode: Option<&str>, overflow: Overflow) -> (Vec<String>, bool) {
+    let as_document = overflow == Overflow::Document && utf16_len(&message) > MAX_MESSAGE_LEN;
+    let parts = match overflow {
+        _ if as_document => vec![message],
+        Overflow::Truncate => vec![truncate_message(&message, parse_mode, MAX_MESSAGE_LEN)],
+        _ => split_message(&message, parse_mode),
+    };
+    (parts, as_document)
+}
+
+pub fn utf16_len(s: &str) -> usize {
+    s.chars().map(char::len_utf16).sum()
+}
+
+/// An open formatting entity that has to be closed at the end of a part
+/// and reopened at the start of the next one.
+#[derive(Clone)]
+struct Tag {
+    name: String,
+    open: String,
+    close: String,
+}
+
+enum Effect {
+    None,
+    Newline,
+    Space,
+    Push(Tag),
+    Pop(String),
+}
+
+/// The smallest unit we never cut through: a character, an escape
+/// sequence, an HTML tag or entity, or a whole MarkdownV2 link.
+struct Atom {
+    start: usize,
+    end: usize,
+    effect: Effect,
+}
+
+fn char_atom(start: usize, c: char) -> Atom {
+    let effect = match c {
+        '\n' => Effect::Newline,
+        c if c.is_whitespace() => Effect::Space,
+        _ => Effect::None,
+    };
+    Atom {
+        start,
+        end: start + c.len_utf8(),
+        effect,
+    }
+}
+
+fn plain_atoms(text: &str) -> Vec<Atom> {
+    text.char_indices().map(|(i, c)| char_atom(i, c)).collect()
+}
+
+fn html_atoms(text: &str) -> Vec<Atom> {
+    let mut atoms = Vec::new();
+    let mut iter = text.char_indices().peekable();
+
+    while let Some((i, c)) = iter.next() {
+        let rest = &text[i..];
+        if c == '<' {
+            if let Some(len) = rest.find('>') {
+                let raw = &rest[..=len];
+                let inner = raw[1..raw.len() - 1].trim();
+                let effect = if let Some(name) = inner.strip_prefix('/') {
+                    Effect::Pop(name.trim().to_lowercase())
+                } else {
+                    let name: String = inner
+                        .chars()
+                        .take_while(|c| !c.is_whitespace())
+                        .collect::<String>()
+                        .to_lowercase();
+                    Effect::Push(Tag {
+                        close: format!("</{}>", name),
+                        name,
+                        open: raw.to_string(),
+                    })
+                };
+                atoms.push(Atom {
+                    start: i,
+                    end: i + raw.len(),
+                    effect,
+                });
+                while iter.peek().is_some_and(|&(j, _)| j < i + raw.len()) {
+                    iter.next();
+                }
+                continue;
+            }
+        }
+        if c == '&' {
+            if let Some(len) = rest.find(';').filter(|&l| l <= 10) {
+                atoms.push(Atom {
+                    start: i,
+                    end: i + len + 1,
+                    effect: Effect::None,
+                });
+                while iter.peek().is_some_and(|&(j, _)| j <= i + len) {
+                    iter.next();
+                }
+                continue;
+            }
+        }
+        atoms.push(char_atom(i, c));
+    }
+    atoms
+}
+
+fn markdown_atoms(text: &str) -> Vec<Atom> {
+    let mut atoms = Vec::new();
+    let mut stack: Vec<String> = Vec::new();
+    let mut i = 0;
+
+    while i < text.len() {
+        let rest = &text[i..];
+        let c = rest.chars().next().unwrap();
+        let in_code = stack.last().is_some_and(|t| t.starts_with('`'));
+
+        let (len, effect) = if c == '\\' && rest.len() > 1 {
+            let next = rest[1..].chars().next().unwrap();
+            (1 + next.len_utf8(), Effect::None)
+        } else if let Some(after) = rest.strip_prefix("```") {
+            if stack.last().is_some_and(|t| t == "```") {
+                stack.pop();
+                (3, Effect::Pop("```".to_string()))
+            } else if in_code {
+                (1, Effect::None)
+            } else {
+                // The rest of the opening line names the language.
+                let lang_len = after.find('\n').unwrap_or(after.len());
+                let open = &rest[..3 + lang_len];
+                stack.push("```".to_string());
+                (
+                    open.len(),
+                    Effect::Push(Tag {
+                        name: "```".to_string(),
+                        open: format!("{}\n", open),
+                        close: "```".to_string(),
+                    }),
+                )
+            }
+        } else if c == '`' {
+            if stack.last().is_some_and(|t| t == "`") {
+                stack.pop();
+                (1, Effect::Pop("`".to_string()))
+            } else if in_code {
+                (1, Effect::None)
+            } else {
+                stack.push("`".to_string());
+                (1, toggle_tag("`"))
+            }
+        } else if in_code {
+            (c.len_utf8(), char_atom(i, c).effect)
+        } else if c == '[' || rest.starts_with("![") {
+            match markdown_link_len(rest) {
+                Some(len) => (len, Effect::None),
+                None => (c.len_utf8(), Effect::None),
+            }
+        } else {
+            let marker = ["__", "||", "*", "_", "~"]
+                .into_iter()
+                .find(|m| rest.starts_with(m));
+            match marker {
+                Some(m) => {
+                    if let Some(pos) = stack.iter().rposition(|t| t == m) {
+                        stack.remove(pos);
+                        (m.len(), Effect::Pop(m.to_string()))
+                    } else {
+                        stack.push(m.to_string());
+                        (m.len(), toggle_tag(m))
+                    }
+                }
+                None => (c.len_utf8(), char_atom(i, c).effect),
+            }
+        };
+
+        atoms.push(Atom {
+            start: i,
+            end: i + len,
+            effect,
+        });
+        i += len;
+    }
+    atoms
+}
+
+fn toggle_tag(marker: &str) -> Effect {
+    Effect::Push(Tag {
+        name: marker.to_string(),
+        open: marker.to_string(),
+        close: marker.to_string(),
+    })
+}
+
+/// Length of a `[text](url)` or `![emoji](tg://...)` link at the start of
+/// `s`, honouring backslash escapes.
+fn markdown_link_len(s: &str) -> Option<usize> {
+    let start = if s.starts_with('!') { 2 } else { 1 };
+    let mut escaped = false;
+    let mut text_end = None;
+    for (i, c) in s[start..].char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            ']' => {
+                text_end = Some(start + i);
+                break;
+            }
+            '\n' => return None,
+            _ => {}
+        }
+    }
+    let text_end = text_end?;
+    if !s[text_end + 1..].starts_with('(') {
+        return None;
+    }
+    let mut escaped = false;
+    for (i, c) in s[text_end + 2..].char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            ')' => return Some(text_end + 2 + i + 1),
+            '\n' => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+fn atoms_for(text: &str, parse_mode: Option<&str>) -> Vec<Atom> {
+    match parse_mode {
+        Some("HTML") => html_atoms(text),
+        Some("MarkdownV2") => markdown_atoms(text),
+        _ => plain_atoms(text),
+    }
+}
+
+fn apply(stack: &mut Vec<Tag>, effect: &Effect) {
+    match effect {
+        Effect::Push(tag) => stack.push(tag.clone()),
+        Effect::Pop(name) => {
+            if let Some(pos) = stack.iter().rposition(|t| &t.name == name) {
+                stack.remove(pos);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn closing(stack: &[Tag]) -> String {
+    stack.iter().rev().map(|t| t.close.as_str()).collect()
+}
+
+fn opening(stack: &[Tag]) -> String {
+    stack.iter().map(|t| t.open.as_str()).collect()
+}
+
+/// One part of a split text: `body` starts with the entities reopened from
+/// the previous part, `close` closes whatever is still open at the cut.
+struct Part {
+    body: String,
+    close: String,
+}
+
+fn split_parts(text: &str, parse_mode: Option<&str>, budget: usize) -> Vec<Part> {
+    let atoms = atoms_for(text, parse_mode);
+    let mut parts = Vec::new();
+    let mut stack: Vec<Tag> = Vec::new();
+    let mut start = 0;
+
+    while start < atoms.len() {
+        let prefix = opening(&stack);
+        let mut len = utf16_len(&prefix);
+        let mut cur = stack.clone();
+        // Candidate cut points: (atom index after the cut, stack at the cut).
+        let mut newline_cut: Option<(usize, Vec<Tag>, usize)> = None;
+        let mut space_cut: Option<(usize, Vec<Tag>, usize)> = None;
+        let mut end = start;
+
+        while end < atoms.len() {
+            let atom = &atoms[end];
+            let mut next = cur.clone();
+            apply(&mut next, &atom.effect);
+            let next_close_len = utf16_len(&closing(&next));
+            let atom_len = utf16_len(&text[atom.start..atom.end]);
+            if len + atom_len + next_close_len > budget && end > start {
+                break;
+            }
+            len += atom_len;
+            cur = next;
+            end += 1;
+            match atom.effect {
+                Effect::Newline => newline_cut = Some((end, cur.clone(), len)),
+                Effect::Space => space_cut = Some((end, cur.clone(), len)),
+                _ => {}
+            }
+        }
+
+        let (cut, cut_stack) = if end == atoms.len() {
+            (end, cur)
+        } else {
+            match (newline_cut, space_cut) {
+                (Some((i, s, l)), _) if l >= budget / 2 => (i, s),
+                (_, Some((i, s, _))) => (i, s),
+                (Some((i, s, _)), None) => (i, s),
+                (None, None) => (end, cur),
+            }
+        };
+
+        let body_start = atoms[start].start;
+        let body_end = atoms[cut - 1].end;
+        parts.push(Part {
+            body: format!("{}{}", prefix, &text[body_start..body_end]),
+            close: closing(&cut_stack),
+        });
+        stack = cut_stack;
+        start = cut;
+    }
+    parts
+}
+
+fn label(index: usize, total: usize, parse_mode: Option<&str>) -> String {
+    match parse_mode {
+        Some("MarkdownV2") => format!("\\({}/{}\\)\n", index, total),
+        _ => format!("({}/{})\n", index, total),
+    }
+}
+
+/// Split `text` into parts that each fit in one message, labelled "(1/3)",
+/// "(2/3)", ... Formatting entities open at a cut are closed at the end of
+/// the part and reopened at the start of the next.
+pub fn split_message(text: &str, parse_mode: Option<&str>) -> Vec<String> {
+    if utf16_len(text) <= MAX_MESSAGE_LEN {
+        return vec![text.to_string()];
+    }
+    let parts = split_parts(text, parse_mode, MAX_MESSAGE_LEN - LABEL_RESERVE);
+    let total = parts.len();
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(i, p)| format!("{}{}{}", label(i + 1, total, parse_mode), p.body, p.close))
+        .collect()
+}
+
+/// Cut `text` down to at most `limit` characters ending with an ellipsis.
+pub fn truncate_message(text: &str, parse_mode: Option<&str>, limit: usize) -> String {
+    if utf16_len(text) <= limit {
+        return text.to_string();
+    }
+    let ellipsis = "…";
+    let budget = limit - utf16_len(ellipsis);
+    match split_parts(text, parse_mode, budget).into_iter().next() {
+        Some(p) => format!("{}{}{}", p.body.trim_end(), ellipsis, p.close),
+        None => String::new(),
+    }
+}
+
+/// Short plain-text caption for a message sent as a document: its first
+/// non-blank line, shortened to fit comfortably in a caption.
+pub fn document_caption(text: &str) -> String {
+    const MAX_CAPTION_CHARS: usize = 200;
+    let first = text
+        .lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty())
+        .unwrap_or("");
+    if first.chars().count() > MAX_CAPTION_CHARS {
+        let cut: String = first.chars().take(MAX_CAPTION_CHARS - 1).collect();
+        format!("{}…", cut)
+    } else {
+        first.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The parts without their "(1/3)" labels.
+    fn bodies(parts: &[String]) -> Vec<&str> {
+        parts
+            .iter()
+            .map(|p| p.split_once('\n').expect("labelled part").1)
+            .collect()
+    }
+
+    fn assert_fits(parts: &[String]) {
+        for p in parts {
+            assert!(
+                utf16_len(p) <= MAX_MESSAGE_LEN,
+                "part is {} long",
+                utf16_len(p)
+            );
+        }
+    }
+
+    /// Whether every HTML tag in `s` is closed, in order.
+    fn html_balanced(s: &str) -> bool {
+        let mut stack = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('<') {
+            let end = start + rest[start..].find('>').unwrap();
+            let inner = &rest[start + 1..end];
+            match inner.strip_prefix('/') {
+                Some(name) => {
+                    if stack.pop() != Some(name) {
+                        return false;
+                    }
+                }
+                None => stack.push(inner.split_whitespace().next().unwrap()),
+            }
+            rest = &rest[end + 1..];
+        }
+        stack.is_empty()
+    }
+
+    #[test]
+    fn short_message_is_left_alone() {
+        assert_eq!(split_message("hello", None), vec!["hello"]);
+        let exact = "a".repeat(MAX_MESSAGE_LEN);
+        assert_eq!(split_message(&exact, None), vec![exact]);
+    }
+
+    #[test]
+    fn plain_text_splits_on_lines() {
+        let line = format!("{}\n", "word ".repeat(19));
+        let text = line.repeat(100);
+        let parts = split_message(&text, None);
+        assert_eq!(parts.len(), 3);
+        assert_fits(&parts);
+        assert!(parts[0].starts_with("(1/3)\n"));
+        assert!(parts[2].starts_with("(3/3)\n"));
+        for body in &bodies(&parts)[..2] {
+            assert!(body.ends_with('\n'));
+        }
+        assert_eq!(bodies(&parts).concat(), text);
+    }
+
+    #[test]
+    fn text_without_whitespace_is_cut_anywhere() {
+        let text = "x".repeat(10_000);
+        let parts = split_message(&text, None);
+        assert_eq!(parts.len(), 3);
+        assert_fits(&parts);
+        assert_eq!(bodies(&parts).concat(), text);
+    }
+
+    #[test]
+    fn surrogate_pairs_count_double_and_stay_whole() {
+        let text = "😀".repeat(3000);
+        assert_eq!(utf16_len(&text), 6000);
+        let parts = split_message(&text, None);
+        assert_eq!(parts.len(), 2);
+        assert_fits(&parts);
+        assert_eq!(bodies(&parts).concat(), text);
+    }
+
+    #[test]
+    fn html_tags_are_closed_and_reopened() {
+        let text = format!(
+            "<b>{}<a href=\"https://example.com\">{}</a></b>",
+            "bold ".repeat(1000),
+            "link ".repeat(1000)
+        );
+        let parts = split_message(&text, Some("HTML"));
+        assert!(parts.len() > 1);
+        assert_fits(&parts);
+        for body in bodies(&parts) {
+            assert!(html_balanced(body), "unbalanced part: {}", body);
+            assert!(body.starts_with("<b>"));
+        }
+        assert!(bodies(&parts)[2].starts_with("<b><a href=\"https://example.com\">"));
+    }
+
+    #[test]
+    fn html_entities_are_not_cut() {
+        let text = "&amp;".repeat(2000);
+        let parts = split_message(&text, Some("HTML"));
+        assert_fits(&parts);
+        for body in bodies(&parts) {
+            assert_eq!(body.matches("&amp;").count() * 5, body.len());
+        }
+    }
+
+    #[test]
+    fn markdown_code_blocks_are_reopened_with_their_language() {
+        let text = format!("```rust\n{}```", "let x = 1;\n".repeat(800));
+        let parts = split_message(&text, Some("MarkdownV2"));
+        assert!(parts.len() > 1);
+        assert_fits(&parts);
+        assert!(parts[0].starts_with("\\(1/"));
+        for body in bodies(&parts) {
+            assert!(body.starts_with("```rust\n"), "part: {}", &body[..20]);
+            assert!(body.ends_with("```"));
+        }
+    }
+
+    #[test]
+    fn markdown_links_are_not_cut() {
+        let link = "[docs](https://example.com/a\\)b) ";
+        let text = link.repeat(300);
+        let parts = split_message(&text, Some("MarkdownV2"));
+        assert!(parts.len() > 1);
+        assert_fits(&parts);
+        for body in bodies(&parts) {
+            assert_eq!(
+                body.matches("[docs](").count(),
+                body.matches("\\)b)").count()
+            );
+        }
+        assert_eq!(bodies(&parts).concat(), text);
+    }
+
+    #[test]
+    fn markdown_formatting_is_closed_at_the_cut() {
+        let text = format!("*{}*", "bold ".repeat(1000));
+        let parts = split_message(&text, Some("MarkdownV2"));
+        assert_eq!(parts.len(), 2);
+        for body in bodies(&parts) {
+            assert!(body.starts_with('*') && body.ends_with('*'));
+        }
+    }
+
+    #[test]
+    fn truncate_leaves_short_text_alone() {
+        assert_eq!(truncate_message("short", None, 10), "short");
+    }
+
+    #[test]
+    fn truncate_ends_with_an_ellipsis() {
+        let out = truncate_message(&"word ".repeat(100), None, 50);
+        assert!(utf16_len(&out) <= 50);
+        assert!(out.ends_with("word…"));
+    }
+
+    #[test]
+    fn truncate_closes_open_tags() {
+        let text = format!("<i>{}</i>", "x ".repeat(100));
+        let out = truncate_message(&text, Some("HTML"), 50);
+        assert!(utf16_len(&out) <= 50);
+        assert!(out.ends_with("…</i>"));
+        assert!(html_balanced(&out));
+    }
+
+    #[test]
+    fn truncate_counts_surrogate_pairs() {
+        let out = truncate_message(&"😀".repeat(100), None, 11);
+        assert_eq!(out, format!("{}…", "😀".repeat(5)));
+    }
+
+    #[test]
+    fn fit_follows_overflow() {
+        let long = "y".repeat(5000);
+        let (parts, as_document) = fit(long.clone(), None, Overflow::Document);
+        assert_eq!((parts, as_document), (vec![long.clone()], true));
+        let (parts, as_document) = fit(long.clone(), None, Overflow::Truncate);
+        assert_eq!(parts.len(), 1);
+        assert!(!as_document);
+        let (parts, _) = fit(long, None, Overflow::Split);
+        assert_eq!(parts.len(), 2);
+        let (_, as_document) = fit("short".to_string(), None, Overflow::Document);
+        assert!(!as_document);
+    }
+}
@@ -0,0 +1,321 @@
+//! `POST /status/<key>`: one Telegram message per key, edited in place,
+//! for jobs that report progress.
+//!
+//! The first post for a key sends a message and later posts edit it.
+//! Edits closer together than `min_edit_interval_secs` are debounced: the
+//! latest text is kept and applied once the interval has passed. A post
+//! with `?final=true` is the key's last and is applied before it is
+//! answered; the next post starts a new message. Keys and their message
+//! ids are kept in `<data_dir>/status.json`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::{Request, Response, StatusCode};
+
+use crate::config::StatusConfig;
+use crate::telegram::{
+    delete_message, edit_message_text, send_telegram_message, Destination, MessageOptions,
+    SendError,
+};
+use crate::{
+    buttons, json_response, persist, read_message, send_error_response, split, AppState, BoxError,
+};
+
+/// How often the background task looks for debounced edits that are due.
+const FLUSH_TICK: Duration = Duration::from_millis(500);
+
+/// Message ids by destination and key, as saved in `status.json`.
+type Saved = BTreeMap<String, BTreeMap<String, i64>>;
+
+/// An edit waiting for the interval to pass.
+struct Pending {
+    text: String,
+    options: MessageOptions,
+}
+
+#[derive(Default)]
+struct Slot {
+    message_id: Option<i64>,
+    last_edit: Option<Instant>,
+    pending: Option<Pending>,
+    /// Set once the key is forgotten; whoever still holds the slot has to
+    /// look the key up again.
+    closed: bool,
+}
+
+type Key = (String, String);
+
+pub struct Statuses {
+    path: PathBuf,
+    min_interval: Duration,
+    slots: Mutex<HashMap<Key, Arc<tokio::sync::Mutex<Slot>>>>,
+    saved: Mutex<Saved>,
+}
+
+impl Statuses {
+    pub fn open(path: PathBuf, config: &StatusConfig) -> Result<Statuses, BoxError> {
+        let saved: Saved = persist::load(&path)?;
+        let mut slots = HashMap::new();
+        for (dest, keys) in &saved {
+            for (key, id) in keys {
+                let slot = Slot {
+                    message_id: Some(*id),
+                    ..Default::default()
+                };
+                slots.insert(
+                    (dest.clone(), key.clone()),
+                    Arc::new(tokio::sync::Mutex::new(slot)),
+                );
+            }
+        }
+        Ok(Statuses {
+            path,
+            min_interval: Duration::from_secs(config.min_edit_interval_secs),
+            slots: Mutex::new(slots),
+            saved: Mutex::new(saved),
+        })
+    }
+
+    fn slot(&self, destination: &str, key: &str) -> Arc<tokio::sync::Mutex<Slot>> {
+        let mut slots = self.slots.lock().unwrap();
+        slots
+            .entry((destination.to_string(), key.to_string()))
+            .or_default()
+            .clone()
+    }
+
+    fn existing(&self, destination: &str, key: &str) -> Option<Arc<tokio::sync::Mutex<Slot>>> {
+        let slots = self.slots.lock().unwrap();
+        slots
+            .get(&(destination.to_string(), key.to_string()))
+            .cloned()
+    }
+
+    /// Update the saved message id for a key; `None` forgets the key.
+    fn save(&self, destination: &str, key: &str, message_id: Option<i64>) {
+        let mut saved = self.saved.lock().unwrap();
+        match message_id {
+            Some(id) => {
+                saved
+                    .entry(destination.to_string())
+                    .or_default()
+                    .insert(key.to_string(), id);
+            }
+            None => {
+                if let Some(keys) = saved.get_mut(destination) {
+                    keys.remove(key);
+                    if keys.is_empty() {
+                        saved.remove(destination);
+                    }
+                }
+            }
+        }
+        if let Err(e) = persist::save(&self.path, &*saved) {
+            error!("failed to save status messages", error = e);
+        }
+    }
+
+    /// Forget a key; the slot is closed so nobody edits through it again.
+    fn close(&self, slot: &mut Slot, destination: &str, key: &str) {
+        slot.closed = true;
+        slot.pending = None;
+        self.slots
+            .lock()
+            .unwrap()
+            .remove(&(destination.to_string(), key.to_string()));
+        self.save(destination, key, None);
+    }
+}
+
+/// Send the key's message, or edit it if there is one. A message that
+/// was deleted in the meantime is sent again.
+async fn apply(
+    state: &AppState,
+    dest: &Destination,
+    key: &str,
+    slot: &mut Slot,
+    text: &str,
+    options: &MessageOptions,
+) -> Result<(&'static str, i64), SendError> {
+    let mut status = "sent";
+    if let Some(id) = slot.message_id {
+        match edit_message_text(state, dest, id, text, options).await {
+            Ok(()) => status = "edited",
+            Err(e) if e.is_message_gone() => {
+                info!("status message is gone, sending it again", key = key);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    if status == "sent" {
+        let id = send_telegram_message(state, dest, text, options).await?;
+        slot.message_id = Some(id);
+        state.statuses.save(&dest.name, key, Some(id));
+    }
+    slot.last_edit = Some(Instant::now());
+
+    let message_id = slot.message_id.unwrap_or_default();
+    if let (true, Some(answers)) = (buttons::has_callbacks(&options.buttons), &state.answers) {
+        answers.track(&dest.name, message_id);
+    }
+    Ok((status, message_id))
+}
+
+fn query_flag(req: &Request<Bytes>, name: &str) -> bool {
+    let query = req.uri().query().unwrap_or("");
+    form_urlencoded::parse(query.as_bytes())
+        .any(|(k, v)| k == name && matches!(v.as_ref(), "" | "1" | "true" | "yes"))
+}
+
+pub async fn handle(
+    req: Request<Bytes>,
+    state: &AppState,
+    dest: &Destination,
+    key: &str,
+) -> Result<Response<Full<Bytes>>, BoxError> {
+    let last = query_flag(&req, "final");
+    let (message, options) = match read_message(&req, state) {
+        Ok(m) => m,
+        Err(e) => return json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": e})),
+    };
+    if options.send_at.is_some() {
+        return json_response(
+            StatusCode::BAD_REQUEST,
+            serde_json::json!({"error": "send_at and delay only work for new messages"}),
+        );
+    }
+    // The status is one message, so long text is cut to fit.
+    let text = split::truncate_message(
+        &message,
+        options.parse_mode.as_deref(),
+        split::MAX_MESSAGE_LEN,
+    );
+
+    let statuses = &state.statuses;
+    loop {
+        let slot = statuses.slot(&dest.name, key);
+        let mut slot = slot.lock().await;
+        if slot.closed {
+            continue;
+        }
+
+        let wait = slot.last_edit.map_or(Duration::ZERO, |t| {
+            statuses.min_interval.saturating_sub(t.elapsed())
+        });
+        if slot.message_id.is_some() && !wait.is_zero() && last {
+            // The last update is applied before we answer, so a post that
+            // starts the next message can't overtake it.
+            tokio::time::sleep(wait).await;
+        } else if slot.message_id.is_some() && !wait.is_zero() {
+            slot.pending = Some(Pending { text, options });
+            return json_response(
+                StatusCode::ACCEPTED,
+                serde_json::json!({
+                    "status": "debounced",
+                    "key": key,
+                    "message_id": slot.message_id,
+                    "chat_id": dest.chat_id,
+                }),
+            );
+        }
+
+        slot.pending = None;
+        return match apply(state, dest, key, &mut slot, &text, &options).await {
+            Ok((status, message_id)) => {
+                if last {
+                    statuses.close(&mut slot, &dest.name, key);
+                }
+                json_response(
+                    StatusCode::OK,
+                    serde_json::json!({
+                        "status": status,
+                        "key": key,
+                        "message_id": message_id,
+                        "chat_id": dest.chat_id,
+                    }),
+                )
+            }
+            Err(e) => send_error_response(&e),
+        };
+    }
+}
+
+/// `DELETE /status/<key>`: delete the key's message and forget the key.
+pub async fn handle_delete(
+    state: &AppState,
+    dest: &Destination,
+    key: &str,
+) -> Result<Response<Full<Bytes>>, BoxError> {
+    let statuses = &state.statuses;
+    let Some(slot) = statuses.existing(&dest.name, key) else {
+        return json_response(
+            StatusCode::NOT_FOUND,
+            serde_json::json!({"error": format!("unknown status: {}", key)}),
+        );
+    };
+    let mut slot = slot.lock().await;
+    if let Some(id) = slot.message_id.filter(|_| !slot.closed) {
+        match delete_message(state, dest, id).await {
+            Ok(()) => {}
+            Err(e) if e.is_message_gone() => {}
+            Err(e) => return send_error_response(&e),
+        }
+    }
+    statuses.close(&mut slot, &dest.name, key);
+    json_response(
+        StatusCode::OK,
+        serde_json::json!({"status": "deleted", "key": key}),
+    )
+}
+
+/// Apply debounced edits once their interval has passed.
+pub async fn run_flusher(state: Arc<AppState>) {
+    let statuses = &state.statuses;
+    loop {
+        tokio::time::sleep(FLUSH_TICK).await;
+
+        let slots: Vec<(Key, Arc<tokio::sync::Mutex<Slot>>)> = statuses
+            .slots
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        for ((dest_name, key), slot) in slots {
+            // A busy slot is being sent or edited right now; its pending
+            // edit can wait for the next tick.
+            let Ok(mut slot) = slot.try_lock() else {
+                continue;
+            };
+            let due = slot
+                .last_edit
+                .is_none_or(|t| t.elapsed() >= statuses.min_interval);
+            if slot.closed || !due {
+                continue;
+            }
+            let Some(pending) = slot.pending.take() else {
+                continue;
+            };
+            let Some(dest) = state.destination(Some(&dest_name)) else {
+                continue;
+            };
+            if let Err(e) = apply(
+                &state,
+                &dest,
+                &key,
+                &mut slot,
+                &pending.text,
+                &pending.options,
+            )
+            .await
+            {
+                warn!("failed to update status message", key = key, error = e);
+            }
+        }
+    }
+}
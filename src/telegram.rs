@@ -0,0 +1,522 @@
+//! Thin client for the Telegram Bot API.
+
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use hyper::body::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::quiet::{self, QuietHours};
+use crate::{metrics, AppState, BoxError};
+
+/// How many times a single call is retried after Telegram answers 429.
+const MAX_FLOOD_RETRIES: u32 = 5;
+
+#[derive(Debug)]
+pub enum SendError {
+    /// Telegram answered with a non-2xx status.
+    Api {
+        status: reqwest::StatusCode,
+        body: String,
+        /// `parameters.retry_after` from a 429 response, in seconds.
+        retry_after: Option<u64>,
+    },
+    /// No usable response (connection refused, timeout, TLS failure, ...).
+    Transport(reqwest::Error),
+}
+
+impl SendError {
+    /// Whether retrying the same request can never succeed, e.g. Telegram
+    /// rejected the markup. Auth and rate-limit errors are not permanent.
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, SendError::Api { status, .. } if *status == reqwest::StatusCode::BAD_REQUEST)
+    }
+
+    /// Whether Telegram says the message we referred to doesn't exist,
+    /// e.g. because someone deleted it.
+    pub fn is_message_gone(&self) -> bool {
+        matches!(self, SendError::Api { status, body, .. }
+            if *status == reqwest::StatusCode::BAD_REQUEST && body.contains("not found"))
+    }
+
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            SendError::Api { retry_after, .. } => *retry_after,
+            SendError::Transport(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::Api { status, body, .. } => {
+                write!(f, "Telegram API error {}: {}", status, body)
+            }
+            SendError::Transport(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+impl From<reqwest::Error> for SendError {
+    fn from(e: reqwest::Error) -> Self {
+        // The URL contains the bot token; never let it reach a log line or
+        // a client.
+        SendError::Transport(e.without_url())
+    }
+}
+
+/// How to send a message, besides its text.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct MessageOptions {
+    /// Telegram parse mode: `HTML` or `MarkdownV2`.
+    #[serde(default)]
+    pub parse_mode: Option<String>,
+    /// Deliver without a notification sound.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub silent: bool,
+    /// Ring even during quiet hours; see [`crate::quiet`].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub urgent: bool,
+    /// Inline keyboard, as rows of buttons.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub buttons: Vec<Vec<Button>>,
+    /// Message id to send this as a reply to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<i64>,
+    /// Collapse this with other messages carrying the same key; see
+    /// [`crate::dedupe`]. Only used before a message is queued.
+    #[serde(skip)]
+    pub dedupe_key: Option<String>,
+    /// Deliver at this time instead of now; see [`crate::scheduled`]. Only
+    /// used before a message is scheduled.
+    #[serde(skip)]
+    pub send_at: Option<DateTime<Utc>>,
+}
+
+/// `reply_parameters` for replying to `message_id`. Sending still works
+/// if that message is gone.
+fn reply_parameters(message_id: i64) -> serde_json::Value {
+    serde_json::json!({"message_id": message_id, "allow_sending_without_reply": true})
+}
+
+/// An inline keyboard button: a link, or a callback whose press is
+/// recorded for `GET /messages/<id>/response`.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Button {
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Callback data; defaults to `text` for buttons without a `url`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub callback: Option<String>,
+}
+
+impl Button {
+    /// The callback data this button sends, if it isn't a link.
+    pub fn callback_data(&self) -> Option<&str> {
+        match &self.url {
+            Some(_) => None,
+            None => Some(self.callback.as_deref().unwrap_or(&self.text)),
+        }
+    }
+}
+
+/// `reply_markup` for `buttons`.
+pub fn inline_keyboard(buttons: &[Vec<Button>]) -> serde_json::Value {
+    let rows: Vec<Vec<serde_json::Value>> = buttons
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|b| match (&b.url, b.callback_data()) {
+                    (Some(url), _) => serde_json::json!({"text": b.text, "url": url}),
+                    (None, data) => serde_json::json!({"text": b.text, "callback_data": data}),
+                })
+                .collect()
+        })
+        .collect();
+    serde_json::json!({ "inline_keyboard": rows })
+}
+
+/// Where a message goes: a chat, and the bot that sends to it.
+pub struct Destination {
+    pub name: String,
+    pub token: String,
+    pub chat_id: i64,
+    pub quiet_hours: Option<QuietHours>,
+}
+
+/// Build a Bot API method URL, e.g. `{base}/bot{token}/sendMessage`.
+pub fn telegram_api_url(base: &str, token: &str, method: &str) -> String {
+    format!("{}/bot{}/{}", base, token, method)
+}
+
+/// Call a Bot API method and return its `result`. `build` fills in the
+/// request body and is invoked again for every retry.
+///
+/// Calls addressed to a chat wait for the rate limiter first. A 429 is
+/// retried after Telegram's `retry_after` as long as that stays within
+/// `max_retry_after_secs`; longer waits are returned to the caller.
+pub async fn call_api<F>(
+    state: &AppState,
+    token: &str,
+    method: &str,
+    chat_id: Option<i64>,
+    build: F,
+) -> Result<serde_json::Value, SendError>
+where
+    F: Fn(reqwest::RequestBuilder) -> reqwest::RequestBuilder,
+{
+    let url = telegram_api_url(&state.telegram_api_base, token, method);
+    let mut retries = 0;
+
+    loop {
+        let waited = match chat_id {
+            Some(id) => state.rate_limiter.acquire(token, id).await,
+            None => state.rate_limiter.acquire_bot(token).await,
+        };
+        state
+            .metrics
+            .observe(metrics::RATE_LIMIT_WAIT, &[], waited.as_secs_f64());
+
+        let started = Instant::now();
+        let result = build(state.http_client.post(&url)).send().await;
+        state.metrics.observe(
+            metrics::TELEGRAM_LATENCY,
+            &[("method", method)],
+            started.elapsed().as_secs_f64(),
+        );
+        let resp = match result {
+            Ok(r) => r,
+            Err(e) => {
+                state.metrics.inc(
+                    metrics::TELEGRAM_ERRORS,
+                    &[("method", method), ("code", "transport")],
+                );
+                return Err(e.into());
+            }
+        };
+        let status = resp.status();
+        if status.is_success() {
+            let body: serde_json::Value = resp.json().await?;
+            return Ok(body["result"].clone());
+        }
+
+        state.metrics.inc(
+            metrics::TELEGRAM_ERRORS,
+            &[("method", method), ("code", status.as_str())],
+        );
+        let body = resp.text().await.unwrap_or_default();
+        let retry_after = serde_json::from_str::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|v| v["parameters"]["retry_after"].as_u64());
+
+        if let Some(secs) = retry_after {
+            if retries < MAX_FLOOD_RETRIES && secs <= state.max_retry_after_secs {
+                retries += 1;
+                warn!(
+                    "telegram rate limited, retrying",
+                    method = method,
+                    retry_in_secs = secs,
+                    retry = retries,
+                    max_retries = MAX_FLOOD_RETRIES
+                );
+                tokio::time::sleep(Duration::from_secs(secs)).await;
+                continue;
+            }
+        }
+
+        return Err(SendError::Api {
+            status,
+            body,
+            retry_after,
+        });
+    }
+}
+
+pub async fn send_telegram_message(
+    state: &AppState,
+    dest: &Destination,
+    text: &str,
+    options: &MessageOptions,
+) -> Result<i64, SendError> {
+    let mut body = serde_json::json!({
+        "chat_id": dest.chat_id,
+        "text": text,
+    });
+    if let Some(mode) = &options.parse_mode {
+        body["parse_mode"] = serde_json::json!(mode);
+    }
+    if quiet::is_silent(state, dest, options) {
+        body["disable_notification"] = serde_json::json!(true);
+    }
+    if !options.buttons.is_empty() {
+        body["reply_markup"] = inline_keyboard(&options.buttons);
+    }
+    if let Some(id) = options.reply_to {
+        body["reply_parameters"] = reply_parameters(id);
+    }
+
+    let result = call_api(
+        state,
+        &dest.token,
+        "sendMessage",
+        Some(dest.chat_id),
+        |req| req.json(&body),
+    )
+    .await;
+    record_delivery(state, dest, text, &result);
+    Ok(message_id(&result?))
+}
+
+/// Replace the text of a sent message. Its keyboard is replaced by
+/// `options.buttons` too, so an edit without buttons removes them.
+pub async fn edit_message_text(
+    state: &AppState,
+    dest: &Destination,
+    message_id: i64,
+    text: &str,
+    options: &MessageOptions,
+) -> Result<(), SendError> {
+    let mut body = serde_json::json!({
+        "chat_id": dest.chat_id,
+        "message_id": message_id,
+        "text": text,
+    });
+    if let Some(mode) = &options.parse_mode {
+        body["parse_mode"] = serde_json::json!(mode);
+    }
+    if !options.buttons.is_empty() {
+        body["reply_markup"] = inline_keyboard(&options.buttons);
+    }
+
+    let result = call_api(
+        state,
+        &dest.token,
+        "editMessageText",
+        Some(dest.chat_id),
+        |req| req.json(&body),
+    )
+    .await;
+    match result {
+        // Editing to the same text isn't worth failing over.
+        Err(SendError::Api { body, .. }) if body.contains("message is not modified") => Ok(()),
+        Err(e) => Err(e),
+        Ok(_) => Ok(()),
+    }
+}
+
+pub async fn delete_message(
+    state: &AppState,
+    dest: &Destination,
+    message_id: i64,
+) -> Result<(), SendError> {
+    let body = serde_json::json!({"chat_id": dest.chat_id, "message_id": message_id});
+    call_api(
+        state,
+        &dest.token,
+        "deleteMessage",
+        Some(dest.chat_id),
+        |req| req.json(&body),
+    )
+    .await?;
+    Ok(())
+}
+
+/// The `message_id` of a sent message.
+fn message_id(message: &serde_json::Value) -> i64 {
+    message["message_id"].as_i64().unwrap_or_default()
+}
+
+fn record_delivery(
+    state: &AppState,
+    dest: &Destination,
+    summary: &str,
+    result: &Result<serde_json::Value, SendError>,
+) {
+    let name = match result {
+        Ok(_) => {
+            state.activity.sent(&dest.name, summary);
+            metrics::MESSAGES_SENT
+        }
+        Err(e) => {
+            state.activity.failed(&dest.name, &e.to_string());
+            metrics::MESSAGES_FAILED
+        }
+    };
+    state.metrics.inc(name, &[("destination", &dest.name)]);
+}
+
+#[derive(Clone, Copy)]
+pub enum MediaKind {
+    Document,
+    Photo,
+}
+
+impl MediaKind {
+    fn method(self) -> &'static str {
+        match self {
+            MediaKind::Document => "sendDocument",
+            MediaKind::Photo => "sendPhoto",
+        }
+    }
+
+    pub fn field(self) -> &'static str {
+        match self {
+            MediaKind::Document => "document",
+            MediaKind::Photo => "photo",
+        }
+    }
+
+    /// Largest file Telegram accepts, in bytes.
+    pub fn max_size(self) -> usize {
+        match self {
+            MediaKind::Document => 50 << 20,
+            MediaKind::Photo => 10 << 20,
+        }
+    }
+
+    /// File name used when the uploader didn't send one.
+    pub fn default_file_name(self) -> &'static str {
+        match self {
+            MediaKind::Document => "file",
+            MediaKind::Photo => "photo.jpg",
+        }
+    }
+}
+
+/// A file to send with `sendDocument` or `sendPhoto`.
+pub struct Upload {
+    pub file_name: String,
+    pub data: Bytes,
+}
+
+pub async fn send_media(
+    state: &AppState,
+    dest: &Destination,
+    kind: MediaKind,
+    upload: &Upload,
+    caption: Option<&str>,
+    options: &MessageOptions,
+) -> Result<i64, SendError> {
+    let result = call_api(
+        state,
+        &dest.token,
+        kind.method(),
+        Some(dest.chat_id),
+        |req| {
+            // Bytes clones are cheap, so each retry rebuilds the form.
+            let file = reqwest::multipart::Part::stream_with_length(
+                reqwest::Body::from(upload.data.clone()),
+                upload.data.len() as u64,
+            )
+            .file_name(upload.file_name.clone());
+            let mut form = reqwest::multipart::Form::new()
+                .text("chat_id", dest.chat_id.to_string())
+                .part(kind.field(), file);
+            if let Some(caption) = caption {
+                form = form.text("caption", caption.to_string());
+                if let Some(mode) = &options.parse_mode {
+                    form = form.text("parse_mode", mode.clone());
+                }
+            }
+            if quiet::is_silent(state, dest, options) {
+                form = form.text("disable_notification", "true");
+            }
+            if !options.buttons.is_empty() {
+                form = form.text(
+                    "reply_markup",
+                    inline_keyboard(&options.buttons).to_string(),
+                );
+            }
+            if let Some(id) = options.reply_to {
+                form = form.text("reply_parameters", reply_parameters(id).to_string());
+            }
+            req.multipart(form)
+        },
+    )
+    .await;
+    let summary = caption.unwrap_or(&upload.file_name);
+    record_delivery(state, dest, summary, &result);
+    Ok(message_id(&result?))
+}
+
+/// Send `text` as a plain-text file attachment, for messages too long to
+/// send inline.
+pub async fn send_text_document(
+    state: &AppState,
+    dest: &Destination,
+    file_name: &str,
+    text: &str,
+    caption: Option<&str>,
+    options: &MessageOptions,
+) -> Result<i64, SendError> {
+    let upload = Upload {
+        file_name: file_name.to_string(),
+        data: Bytes::from(text.to_string()),
+    };
+    // The caption is plain text whatever the message's parse mode was.
+    let options = MessageOptions {
+        parse_mode: None,
+        ..options.clone()
+    };
+    send_media(state, dest, MediaKind::Document, &upload, caption, &options).await
+}
+
+/// Poll Telegram's getUpdates endpoint until we find a message from the
+/// configured username. Returns the chat_id for that user.
+pub async fn resolve_chat_id(
+    client: &reqwest::Client,
+    api_base: &str,
+    token: &str,
+    username: &str,
+) -> Result<i64, BoxError> {
+    let url = telegram_api_url(api_base, token, "getUpdates");
+
+    // Normalize: strip leading @ if present
+    let username = username.strip_prefix('@').unwrap_or(username);
+
+    info!(
+        "waiting for a message to the bot; tell them to open it and send /start",
+        username = username
+    );
+
+    let mut offset: Option<i64> = None;
+
+    loop {
+        let mut params = serde_json::json!({"timeout": 30});
+        if let Some(off) = offset {
+            params["offset"] = serde_json::json!(off);
+        }
+
+        // reqwest errors carry the URL, and with it the bot token.
+        let resp = client
+            .post(&url)
+            .json(&params)
+            .send()
+            .await
+            .map_err(reqwest::Error::without_url)?;
+        let body: serde_json::Value = resp.json().await.map_err(reqwest::Error::without_url)?;
+
+        if let Some(updates) = body["result"].as_array() {
+            for update in updates {
+                // Track offset so we don't re-process old updates
+                if let Some(id) = update["update_id"].as_i64() {
+                    offset = Some(id + 1);
+                }
+
+                let msg = &update["message"];
+                if let Some(from_username) = msg["from"]["username"].as_str() {
+                    if from_username.eq_ignore_ascii_case(username) {
+                        if let Some(chat_id) = msg["chat"]["id"].as_i64() {
+                            info!("resolved username", username = username, chat_id = chat_id);
+                            return Ok(chat_id);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Rendering JSON payloads into message text with minijinja templates.
+//!
+//! Values interpolated with `{{ ... }}` are escaped for the template's parse
+//! mode, so payload text can't break the markup around it. Use the `safe`
+//! filter to insert a value as-is.
+
+use minijinja::{Environment, Error, Output, State, Value};
+
+const NAME: &str = "message";
+
+pub struct Template {
+    env: Environment<'static>,
+    /// Telegram parse mode of the rendered text.
+    pub parse_mode: Option<&'static str>,
+}
+
+/// Escape `text` so Telegram shows it literally under `parse_mode`.
+pub fn escape(text: &str, parse_mode: Option<&str>) -> String {
+    match parse_mode {
+        Some("HTML") => text
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;"),
+        Some("MarkdownV2") => {
+            let mut out = String::with_capacity(text.len());
+            for c in text.chars() {
+                if "_*[]()~`>#+-=|{}.!\\".contains(c) {
+                    out.push('\\');
+                }
+                out.push(c);
+            }
+            out
+        }
+        _ => text.to_string(),
+    }
+}
+
+impl Template {
+    pub fn new(source: &str, parse_mode: Option<&'static str>) -> Result<Template, String> {
+        let mut env = Environment::new();
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        env.set_formatter(move |out: &mut Output, _: &State, value: &Value| {
+            format_value(out, value, parse_mode)
+        });
+        env.add_template_owned(NAME, source.to_string())
+            .map_err(|e| e.to_string())?;
+        Ok(Template { env, parse_mode })
+    }
+
+    /// Render `ctx`. Runs of blank lines left behind by skipped blocks are
+    /// collapsed and the result is trimmed; an empty result means there is
+    /// nothing to send.
+    pub fn render(&self, ctx: &serde_json::Value) -> Result<String, String> {
+        let rendered = self
+            .env
+            .get_template(NAME)
+            .and_then(|t| t.render(ctx))
+            .map_err(|e| e.to_string())?;
+
+        let mut out = String::with_capacity(rendered.len());
+        let mut blank = 0;
+        for line in rendered.trim().lines() {
+            let line = line.trim_end();
+            if line.is_empty() {
+                blank += 1;
+                if blank > 1 {
+                    continue;
+                }
+            } else {
+                blank = 0;
+            }
+            out.push_str(line);
+            out.push('\n');
+        }
+        Ok(out.trim_end().to_string())
+    }
+}
+
+fn format_value(out: &mut Output, value: &Value, parse_mode: Option<&str>) -> Result<(), Error> {
+    if value.is_undefined() || value.is_none() {
+        return Ok(());
+    }
+    let text = match value.as_str() {
+        Some(s) => s.to_string(),
+        None => value.to_string(),
+    };
+    if value.is_safe() {
+        out.write_str(&text)?;
+    } else {
+        out.write_str(&escape(&text, parse_mode))?;
+    }
+    Ok(())
+}
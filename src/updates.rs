@@ -0,0 +1,75 @@
+//! Receiving updates from Telegram. With `updates` configured, every bot
+//! long-polls `getUpdates` and hands button presses to [`buttons`], and
+//! with `updates.admins` set, messages to [`commands`].
+//!
+//! `getUpdates` doesn't work while a bot has a webhook set; Telegram
+//! answers 409 and we keep retrying until it is removed.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::telegram::telegram_api_url;
+use crate::{buttons, commands, AppState};
+
+/// Pause after a failed poll before trying again.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Poll updates for the bot `token` forever.
+pub async fn run(state: Arc<AppState>, token: String, poll_timeout_secs: u64) {
+    let url = telegram_api_url(&state.telegram_api_base, &token, "getUpdates");
+    let mut offset: Option<i64> = None;
+    let mut allowed_updates = vec!["callback_query"];
+    if !state.admins.is_empty() {
+        allowed_updates.push("message");
+        commands::register(&state, &token).await;
+    }
+
+    loop {
+        let mut params = serde_json::json!({
+            "timeout": poll_timeout_secs,
+            "allowed_updates": allowed_updates,
+        });
+        if let Some(off) = offset {
+            params["offset"] = serde_json::json!(off);
+        }
+
+        // reqwest errors carry the URL, and with it the bot token.
+        let result = async {
+            let resp = state.http_client.post(&url).json(&params).send().await?;
+            let status = resp.status();
+            let body: serde_json::Value = resp.json().await?;
+            Ok::<_, reqwest::Error>((status, body))
+        }
+        .await
+        .map_err(reqwest::Error::without_url);
+
+        let updates = match result {
+            Ok((status, body)) if status.is_success() => body["result"].clone(),
+            Ok((status, body)) => {
+                warn!(
+                    "getUpdates failed",
+                    status = status.as_u16(),
+                    description = body["description"].as_str().unwrap_or_default()
+                );
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+            Err(e) => {
+                warn!("getUpdates failed", error = e);
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+        };
+
+        for update in updates.as_array().into_iter().flatten() {
+            if let Some(id) = update["update_id"].as_i64() {
+                offset = Some(id + 1);
+            }
+            if update["callback_query"].is_object() {
+                buttons::on_callback(&state, &token, &update["callback_query"]).await;
+            } else if update["message"].is_object() && !state.admins.is_empty() {
+                commands::on_message(&state, &token, &update["message"]).await;
+            }
+        }
+    }
+}
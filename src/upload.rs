@@ -0,0 +1,139 @@
+//! `POST /document` and `POST /photo`: forward an uploaded file to
+//! Telegram's `sendDocument` / `sendPhoto`.
+//!
+//! The file is taken from a `multipart/form-data` body (fields `file`,
+//! `caption`, `parse_mode`, `reply_to`), or the raw body is used as the
+//! file, named by the `telegram-filename` header.
+
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::{Request, Response, StatusCode};
+
+use crate::split::{truncate_message, MAX_CAPTION_LEN};
+use crate::telegram::{send_media, Destination, MediaKind, MessageOptions, Upload};
+use crate::{
+    json_response, parse_mode_header, parse_mode_name, reply_to_header, send_error_response,
+    AppState, BoxError,
+};
+
+/// Largest upload body: Telegram's 50 MB file limit, plus room for the
+/// multipart framing and the other fields.
+pub const MAX_BODY_BYTES: usize = 51 << 20;
+
+fn bad_request(message: String) -> Result<Response<Full<Bytes>>, BoxError> {
+    json_response(
+        StatusCode::BAD_REQUEST,
+        serde_json::json!({ "error": message }),
+    )
+}
+
+fn header_str(req: &Request<Bytes>, name: &str) -> Option<String> {
+    req.headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+pub async fn handle_upload(
+    req: Request<Bytes>,
+    state: &AppState,
+    dest: &Destination,
+    kind: MediaKind,
+) -> Result<Response<Full<Bytes>>, BoxError> {
+    let mut parse_mode = parse_mode_header(req.headers());
+    let mut caption = header_str(&req, "telegram-caption");
+    let mut reply_to = reply_to_header(req.headers());
+    let boundary = req
+        .headers()
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|ct| multer::parse_boundary(ct).ok());
+
+    let upload = match boundary {
+        Some(boundary) => {
+            let mut multipart =
+                multer::Multipart::new(Full::new(req.into_body()).into_data_stream(), boundary);
+            let mut upload = None;
+            loop {
+                let field = match multipart.next_field().await {
+                    Ok(Some(f)) => f,
+                    Ok(None) => break,
+                    Err(e) => return bad_request(format!("invalid multipart body: {}", e)),
+                };
+                let name = field.name().unwrap_or("").to_string();
+                let is_file = field.file_name().is_some()
+                    || matches!(name.as_str(), "file" | "document" | "photo");
+                let result = if is_file {
+                    let file_name = field
+                        .file_name()
+                        .map(String::from)
+                        .unwrap_or_else(|| kind.default_file_name().to_string());
+                    field.bytes().await.map(|data| {
+                        upload = Some(Upload { file_name, data });
+                    })
+                } else if name == "caption" {
+                    field.text().await.map(|t| caption = Some(t))
+                } else if name == "parse_mode" {
+                    field
+                        .text()
+                        .await
+                        .map(|t| parse_mode = parse_mode_name(&t).map(String::from))
+                } else if name == "reply_to" {
+                    field
+                        .text()
+                        .await
+                        .map(|t| reply_to = t.trim().parse().ok().or(reply_to))
+                } else {
+                    Ok(())
+                };
+                if let Err(e) = result {
+                    return bad_request(format!("invalid multipart body: {}", e));
+                }
+            }
+            match upload {
+                Some(u) => u,
+                None => return bad_request("missing file field".to_string()),
+            }
+        }
+        None => {
+            let file_name = header_str(&req, "telegram-filename")
+                .unwrap_or_else(|| kind.default_file_name().to_string());
+            let data = req.into_body();
+            Upload { file_name, data }
+        }
+    };
+
+    if upload.data.is_empty() {
+        return bad_request("empty file".to_string());
+    }
+    if upload.data.len() > kind.max_size() {
+        return json_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            serde_json::json!({
+                "error": format!(
+                    "file is larger than Telegram's {} MB limit for {}s",
+                    kind.max_size() >> 20,
+                    kind.field()
+                )
+            }),
+        );
+    }
+
+    let caption = caption
+        .filter(|c| !c.trim().is_empty())
+        .map(|c| truncate_message(&c, parse_mode.as_deref(), MAX_CAPTION_LEN));
+
+    let options = MessageOptions {
+        parse_mode,
+        reply_to,
+        ..Default::default()
+    };
+    match send_media(state, dest, kind, &upload, caption.as_deref(), &options).await {
+        Ok(message_id) => json_response(
+            StatusCode::OK,
+            serde_json::json!({"status": "sent", "message_id": message_id, "chat_id": dest.chat_id}),
+        ),
+        Err(e) => send_error_response(&e),
+    }
+}
@@ -0,0 +1,38 @@
+//! Small helpers used across modules: building HTML for the webhook
+//! renderers, and the current Unix time.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+
+use crate::template::escape;
+
+/// Escape `s` for Telegram HTML.
+pub fn h(s: &str) -> String {
+    escape(s, Some("HTML"))
+}
+
+/// The string at JSON `pointer` in `v`, or `""` if there is none.
+pub fn str_at<'a>(v: &'a Value, pointer: &str) -> &'a str {
+    v.pointer(pointer).and_then(Value::as_str).unwrap_or("")
+}
+
+/// An HTML link to `url`, or just the text if `url` is empty.
+pub fn link(url: &str, text: &str) -> String {
+    if url.is_empty() {
+        h(text)
+    } else {
+        format!("<a href=\"{}\">{}</a>", h(url), h(text))
+    }
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+pub fn now_secs() -> u64 {
+    now_millis() / 1000
+}